@@ -58,7 +58,7 @@ fn handle_response(
                 *bulk_tx = Some(bulk);
             } else {
                 assert!(bulk.endpoint == Endpoint::new(0x81));
-                let raw = bulk.buffer_from_raw();
+                let raw = bulk.as_slice();
                 let s: Vec<u8> = raw.iter().cloned().filter(|c| *c < 127).collect();
                 print!("{}", String::from_utf8_lossy(s.as_slice()).to_string());
                 if raw[0] == SYNC_BYTE
@@ -143,7 +143,7 @@ fn main() -> Result<(), std::io::Error> {
         if device.device.id_vendor == 0x483 && device.device.id_product == 0x5740 {
             println!("Found one STM32 device. (Note if there  is more than one STM connected to the host the rest will be ignored.");
             let mut usb = UsbCore::from_device(&device).expect("Could not open device");
-            println!("Capabilities: 0x{:02X?}", usb.capabilities());
+            println!("Capabilities: {:?}", usb.capabilities());
             let _ = usb.claim_interface(0).is_ok();
             println!(
                 "Manufacturer: {}",
@@ -0,0 +1,9 @@
+use usbapi::*;
+/// Print connected devices as a tree of hubs and ports
+fn main() -> Result<(), std::io::Error> {
+    let usb = UsbEnumerate::from_sysfs()?;
+    for root in usb.tree() {
+        print!("{}", root);
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use usbapi::descriptors::configuration::Configuration;
+use usbapi::descriptors::device::Device;
+use usbapi::descriptors::endpoint::Endpoint;
+use usbapi::descriptors::interface::Interface;
+use usbapi::usbip::protocol::USBIP_PORT;
+use usbapi::usbip::{EmulatedDevice, EmulatedExport, UsbIpServer};
+/// Export an emulated device that echoes bulk OUT data on bulk IN.
+/// Attach it with: usbip attach -r localhost -b 1-1
+struct Echo {
+    device: Device,
+    buffer: Vec<u8>,
+}
+
+impl EmulatedDevice for Echo {
+    fn descriptor(&self) -> &Device {
+        &self.device
+    }
+
+    fn string(&self, id: u8) -> Option<String> {
+        match id {
+            1 => Some("usbapi-rs".into()),
+            2 => Some("Echo".into()),
+            _ => None,
+        }
+    }
+
+    fn bulk_out(&mut self, _ep: u8, data: &[u8]) -> Result<usize, i32> {
+        self.buffer.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn bulk_in(&mut self, _ep: u8, length: usize) -> Result<Option<Vec<u8>>, i32> {
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let length = length.min(self.buffer.len());
+        Ok(Some(self.buffer.drain(..length).collect()))
+    }
+}
+
+fn endpoint(endpoint_address: u8) -> Endpoint {
+    Endpoint {
+        length: 7,
+        kind: 5,
+        endpoint_address,
+        bm_attributes: 0x02,
+        max_packet_size: 512,
+        interval: 0,
+    }
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let interface = Interface {
+        length: 9,
+        kind: 4,
+        interface_number: 0,
+        alternate_setting: 0,
+        num_endpoints: 2,
+        interface_class: 0xFF,
+        interface_sub_class: 0,
+        interface_protocol: 0,
+        iinterface: 0,
+        endpoints: vec![endpoint(0x81), endpoint(0x01)],
+    };
+    let device = Device {
+        length: 18,
+        kind: 1,
+        bcd_usb: 0x0200,
+        device_class: 0,
+        device_sub_class: 0,
+        device_protocol: 0,
+        max_packet_size0: 64,
+        id_vendor: 0x1209,
+        id_product: 0x0001,
+        bcd_device: 0x0100,
+        imanufacturer: 1,
+        iproduct: 2,
+        iserial: 0,
+        num_configurations: 1,
+        configurations: vec![Configuration::from_interfaces(1, 0x80, 50, vec![interface])],
+    };
+
+    let mut server = UsbIpServer::new();
+    server.export(EmulatedExport::new(
+        Echo {
+            device,
+            buffer: vec![],
+        },
+        "1-1",
+    ));
+    server.serve(("0.0.0.0", USBIP_PORT))
+}
@@ -0,0 +1,22 @@
+use usbapi::usbip::UsbIpClient;
+/// List devices exported by a usbipd server and print their descriptors.
+/// Usage: cargo run --example usbip_list [host[:port]] [busid]
+fn main() -> Result<(), std::io::Error> {
+    let mut args = std::env::args().skip(1);
+    let mut host = args.next().unwrap_or_else(|| "localhost".into());
+    if !host.contains(':') {
+        host = format!("{}:3240", host);
+    }
+    for dev in UsbIpClient::list_devices(host.as_str())? {
+        println!(
+            "{} {:04x}:{:04x} {}",
+            dev.busid, dev.id_vendor, dev.id_product, dev.path
+        );
+    }
+
+    if let Some(busid) = args.next() {
+        let mut usb = UsbIpClient::import(host.as_str(), &busid)?;
+        println!("{}", usb.descriptors()?);
+    }
+    Ok(())
+}
@@ -51,4 +51,63 @@ impl Configuration {
             unknown_descriptors: vec![],
         })
     }
+
+    /// Create a configuration, lengths and counts are filled in by to_bytes()
+    pub fn from_interfaces(
+        configuration_value: u8,
+        bmattributes: u8,
+        max_power: u8,
+        interfaces: Vec<Interface>,
+    ) -> Self {
+        Configuration {
+            length: 9,
+            kind: 2,
+            total_length: 0,
+            num_interfaces: 0,
+            configuration_value,
+            iconfiguration: 0,
+            bmattributes,
+            max_power,
+            interfaces,
+            unknown_descriptors: vec![],
+        }
+    }
+
+    pub fn configuration_value(&self) -> u8 {
+        self.configuration_value
+    }
+
+    /// Number of interfaces, alternate settings are not counted
+    pub fn num_interfaces(&self) -> u8 {
+        let mut numbers: Vec<u8> = self.interfaces.iter().map(|i| i.interface_number).collect();
+        numbers.dedup();
+        numbers.len().max(self.num_interfaces as usize) as u8
+    }
+
+    /// Serialize the whole configuration as returned by GET_DESCRIPTOR.
+    /// Unknown (class specific) descriptors are put after the interfaces
+    /// since their original position is not kept.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            9,
+            2,
+            0,
+            0,
+            self.num_interfaces(),
+            self.configuration_value,
+            self.iconfiguration,
+            self.bmattributes,
+            self.max_power,
+        ];
+        for iface in &self.interfaces {
+            bytes.extend(iface.to_bytes());
+        }
+        for unknown in &self.unknown_descriptors {
+            bytes.extend_from_slice(unknown);
+        }
+        let total_length = bytes.len() as u16;
+        bytes[2] = (total_length & 0xFF) as u8;
+        bytes[3] = (total_length >> 8) as u8;
+        bytes
+    }
 }
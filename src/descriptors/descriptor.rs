@@ -1,6 +1,7 @@
 use super::configuration::Configuration;
 use super::device::Device;
 use super::endpoint::Endpoint;
+use super::hub::HubDescriptor;
 use super::interface::Interface;
 use std::io::BufReader;
 use std::io::{Bytes, Read};
@@ -17,7 +18,7 @@ pub enum DescriptorType {
     Interface(Interface),
     Endpoint(Endpoint),
     ClassSpecific,
-    Hub,
+    Hub(HubDescriptor),
     SsEndpointCompanion,
     Unknown(Vec<u8>),
 }
@@ -33,7 +34,7 @@ impl Iterator for Descriptor {
         }
 
         let dlength = self.descriptor[0] as usize;
-        if dlength > self.descriptor.len() || dlength == 2 {
+        if self.descriptor.len() < 2 || dlength > self.descriptor.len() || dlength == 2 {
             eprintln!("Invalid descriptor field > vec.len() bailout");
             return None;
         }
@@ -46,6 +47,7 @@ impl Iterator for Descriptor {
             3 => DescriptorType::String("FIXME handle string type".to_string()),
             4 => DescriptorType::Interface(Interface::new(&mut iter)?),
             5 => DescriptorType::Endpoint(Endpoint::new(&mut iter)?),
+            0x29 | 0x2A => DescriptorType::Hub(HubDescriptor::new(&mut iter)?),
             _ => {
                 let res = DescriptorType::Unknown(self.descriptor[..dlength].to_vec());
                 //                if dlength == 0 {
@@ -80,10 +82,7 @@ impl Descriptor {
                 return None;
             }
         };
-        match Self::from_bytes(file.bytes()) {
-            Ok(d) => Some(d),
-            Err(_) => None,
-        }
+        Self::from_bytes(BufReader::new(file).bytes()).ok()
     }
 
     pub fn from_bytes<T>(bytes: Bytes<T>) -> Result<Self, std::io::Error>
@@ -98,3 +97,34 @@ impl Descriptor {
         Ok(desc)
     }
 }
+
+/// Decode a string descriptor (without the setup packet) to a String
+pub(crate) fn string_from_descriptor(data: &[u8], id: u8) -> String {
+    let length = data.len();
+    if length % 2 != 0 || length <= 2 {
+        log::error!(
+            "Received an odd or short descriptor string of length {} for ID {}",
+            length,
+            id
+        );
+        return "Invalid descriptor".into();
+    }
+    let n = length / 2;
+    let mut x = [0; 2];
+    let mut utf16 = Vec::with_capacity(n);
+    for i in 1..n {
+        x.copy_from_slice(&data[2 * i..2 * i + 2]);
+        utf16.push(u16::from_le_bytes(x));
+    }
+    String::from_utf16_lossy(&utf16)
+}
+
+/// Encode s as a string descriptor
+pub(crate) fn string_to_descriptor(s: &str) -> Vec<u8> {
+    let mut bytes = vec![0, 3];
+    for c in s.encode_utf16().take(126) {
+        bytes.extend_from_slice(&c.to_le_bytes());
+    }
+    bytes[0] = bytes.len() as u8;
+    bytes
+}
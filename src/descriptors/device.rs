@@ -62,4 +62,30 @@ impl Device {
             configurations: vec![],
         })
     }
+
+    /// Serialize the device descriptor (18 bytes), configurations are not included.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            18,
+            1,
+            (self.bcd_usb & 0xFF) as u8,
+            (self.bcd_usb >> 8) as u8,
+            self.device_class,
+            self.device_sub_class,
+            self.device_protocol,
+            self.max_packet_size0,
+            (self.id_vendor & 0xFF) as u8,
+            (self.id_vendor >> 8) as u8,
+            (self.id_product & 0xFF) as u8,
+            (self.id_product >> 8) as u8,
+            (self.bcd_device & 0xFF) as u8,
+            (self.bcd_device >> 8) as u8,
+            self.imanufacturer,
+            self.iproduct,
+            self.iserial,
+            self.configurations
+                .len()
+                .max(self.num_configurations as usize) as u8,
+        ]
+    }
 }
@@ -36,4 +36,16 @@ impl Endpoint {
             interval: *iter.next()?,
         })
     }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            7,
+            5,
+            self.endpoint_address,
+            self.bm_attributes,
+            (self.max_packet_size & 0xFF) as u8,
+            (self.max_packet_size >> 8) as u8,
+            self.interval,
+        ]
+    }
 }
@@ -0,0 +1,122 @@
+#[cfg(feature = "serde")]
+use serde::Serialize;
+use std::fmt;
+use std::slice::Iter;
+
+pub const HUB_DESCRIPTOR: u8 = 0x29;
+pub const SUPERSPEED_HUB_DESCRIPTOR: u8 = 0x2A;
+
+/// wHubCharacteristics bits 1:0
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerSwitching {
+    Ganged,
+    PerPort,
+    None,
+}
+
+/// USB 2.0 (0x29) or SuperSpeed (0x2A) hub descriptor
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Debug, Clone)]
+pub struct HubDescriptor {
+    pub length: u8,
+    pub kind: u8,
+    pub num_ports: u8,
+    pub characteristics: u16,
+    /// In 2 ms units
+    pub power_on_to_power_good: u8,
+    pub controller_current: u8,
+    /// SuperSpeed only
+    pub header_decode_latency: u8,
+    /// SuperSpeed only, in ns
+    pub hub_delay: u16,
+    /// Bit n set if the device on port n is not removable
+    pub device_removable: Vec<u8>,
+}
+
+impl fmt::Display for HubDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = format!("bLength: {}\n", self.length);
+        d += &format!("bDescriptorType: 0x{:02X}\n", self.kind);
+        d += &format!("bNbrPorts: {}\n", self.num_ports);
+        d += &format!("wHubCharacteristics: 0x{:04X}\n", self.characteristics);
+        d += &format!("bPwrOn2PwrGood: {}\n", self.power_on_to_power_good);
+        d += &format!("bHubContrCurrent: {}\n", self.controller_current);
+        if self.is_superspeed() {
+            d += &format!("bHubHdrDecLat: {}\n", self.header_decode_latency);
+            d += &format!("wHubDelay: {}\n", self.hub_delay);
+        }
+        d += &format!("DeviceRemovable: {:02X?}\n", self.device_removable);
+        write!(f, "{}", d)
+    }
+}
+
+impl HubDescriptor {
+    pub fn new(iter: &mut Iter<u8>) -> Option<Self> {
+        let length = *iter.next()?;
+        let kind = *iter.next()?;
+        let num_ports = *iter.next()?;
+        let characteristics = *iter.next()? as u16 | (*iter.next()? as u16) << 8;
+        let power_on_to_power_good = *iter.next()?;
+        let controller_current = *iter.next()?;
+        let (header_decode_latency, hub_delay, removable_length) =
+            if kind == SUPERSPEED_HUB_DESCRIPTOR {
+                let latency = *iter.next()?;
+                let delay = *iter.next()? as u16 | (*iter.next()? as u16) << 8;
+                (latency, delay, 2)
+            } else {
+                // One bit per port plus the reserved bit 0
+                (0, 0, (num_ports as usize + 8) / 8)
+            };
+        let mut device_removable = Vec::with_capacity(removable_length);
+        for _ in 0..removable_length {
+            device_removable.push(*iter.next()?);
+        }
+        Some(HubDescriptor {
+            length,
+            kind,
+            num_ports,
+            characteristics,
+            power_on_to_power_good,
+            controller_current,
+            header_decode_latency,
+            hub_delay,
+            device_removable,
+        })
+    }
+
+    pub fn is_superspeed(&self) -> bool {
+        self.kind == SUPERSPEED_HUB_DESCRIPTOR
+    }
+
+    pub fn power_switching(&self) -> PowerSwitching {
+        match self.characteristics & 0x03 {
+            0 => PowerSwitching::Ganged,
+            1 => PowerSwitching::PerPort,
+            _ => PowerSwitching::None,
+        }
+    }
+
+    pub fn is_compound(&self) -> bool {
+        self.characteristics & 0x04 != 0
+    }
+
+    /// USB 2.0 only, port indicators can be set with PORT_INDICATOR
+    pub fn has_port_indicators(&self) -> bool {
+        !self.is_superspeed() && self.characteristics & 0x80 != 0
+    }
+
+    /// Time from port power on until power is good
+    pub fn power_on_to_power_good_ms(&self) -> u32 {
+        self.power_on_to_power_good as u32 * 2
+    }
+
+    /// Ports are numbered from 1
+    pub fn is_removable(&self, port: u8) -> bool {
+        let byte = port as usize / 8;
+        let bit = port % 8;
+        self.device_removable
+            .get(byte)
+            .map_or(true, |removable| removable & (1 << bit) == 0)
+    }
+}
@@ -52,4 +52,23 @@ impl Interface {
             endpoints: vec![],
         })
     }
+
+    /// Serialize the interface followed by its endpoints
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![
+            9,
+            4,
+            self.interface_number,
+            self.alternate_setting,
+            self.endpoints.len() as u8,
+            self.interface_class,
+            self.interface_sub_class,
+            self.interface_protocol,
+            self.iinterface,
+        ];
+        for endpoint in &self.endpoints {
+            bytes.extend(endpoint.to_bytes());
+        }
+        bytes
+    }
 }
@@ -2,4 +2,5 @@ pub mod configuration;
 pub mod descriptor;
 pub mod device;
 pub mod endpoint;
+pub mod hub;
 pub mod interface;
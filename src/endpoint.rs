@@ -33,17 +33,15 @@ impl From<Endpoint> for u8 {
 
 impl Endpoint {
     pub fn new(ep: u8) -> Self {
-        Self { 0: ep }
+        Self(ep)
     }
 
     pub fn bulk_out(ep: u8) -> Self {
-        Self { 0: (ep & 0xF) }
+        Self(ep & 0xF)
     }
 
     pub fn bulk_in(ep: u8) -> Self {
-        Self {
-            0: ENDPOINT_IN | (ep & 0xF),
-        }
+        Self(ENDPOINT_IN | (ep & 0xF))
     }
 
     pub fn is_control(&self) -> bool {
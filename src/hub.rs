@@ -0,0 +1,398 @@
+//! Hub class requests, port status and port power control.
+//! Works on any UsbCore like transport, the hub interface does not need to be claimed.
+use crate::descriptors::hub::*;
+use crate::request::*;
+use crate::{Speed, TimeoutMillis, UsbCoreBlocking, UsbCoreDriver};
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+const HUB_REQUEST_TIMEOUT_MS: u32 = 1000;
+
+/// Port features for SET_FEATURE and CLEAR_FEATURE to a hub port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum PortFeature {
+    Connection = 0,
+    Enable = 1,
+    Suspend = 2,
+    OverCurrent = 3,
+    Reset = 4,
+    /// SuperSpeed only
+    LinkState = 5,
+    Power = 8,
+    LowSpeed = 9,
+    ConnectionChange = 16,
+    EnableChange = 17,
+    SuspendChange = 18,
+    OverCurrentChange = 19,
+    ResetChange = 20,
+    Test = 21,
+    Indicator = 22,
+    U1Timeout = 23,
+    U2Timeout = 24,
+    LinkStateChange = 25,
+    ConfigErrorChange = 26,
+    RemoteWakeMask = 27,
+    BhReset = 28,
+    BhResetChange = 29,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortIndicator {
+    Automatic = 0,
+    Amber = 1,
+    Green = 2,
+    Off = 3,
+}
+
+/// wPortStatus and wPortChange returned by GET_STATUS to a port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortStatus {
+    pub status: u16,
+    pub change: u16,
+    pub superspeed: bool,
+}
+
+impl PortStatus {
+    pub fn connected(&self) -> bool {
+        self.status & 0x0001 != 0
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.status & 0x0002 != 0
+    }
+
+    /// USB 2.0 only
+    pub fn suspended(&self) -> bool {
+        !self.superspeed && self.status & 0x0004 != 0
+    }
+
+    pub fn over_current(&self) -> bool {
+        self.status & 0x0008 != 0
+    }
+
+    pub fn resetting(&self) -> bool {
+        self.status & 0x0010 != 0
+    }
+
+    pub fn powered(&self) -> bool {
+        if self.superspeed {
+            self.status & 0x0200 != 0
+        } else {
+            self.status & 0x0100 != 0
+        }
+    }
+
+    /// USB 2.0 only, test mode is active
+    pub fn test_mode(&self) -> bool {
+        !self.superspeed && self.status & 0x0800 != 0
+    }
+
+    /// USB 2.0 only, indicator is software controlled
+    pub fn indicator_control(&self) -> bool {
+        !self.superspeed && self.status & 0x1000 != 0
+    }
+
+    /// SuperSpeed only, U0..U3, Disabled=4, RxDetect=5 ...
+    pub fn link_state(&self) -> Option<u8> {
+        if self.superspeed {
+            Some(((self.status >> 5) & 0x0F) as u8)
+        } else {
+            None
+        }
+    }
+
+    /// Speed of the attached device, Unknown if nothing is connected.
+    /// Always Speed::Super on a SuperSpeed hub: the wPortStatus speed field
+    /// only defines 5 Gbit/s and a SuperSpeedPlus link is only seen in the
+    /// extended port status, which is not read. Use UsbDevice.speed of
+    /// the attached device for the negotiated speed.
+    pub fn speed(&self) -> Speed {
+        if !self.connected() {
+            Speed::Unknown
+        } else if self.superspeed {
+            Speed::Super
+        } else if self.status & 0x0200 != 0 {
+            Speed::Low
+        } else if self.status & 0x0400 != 0 {
+            Speed::High
+        } else {
+            Speed::Full
+        }
+    }
+
+    pub fn connection_changed(&self) -> bool {
+        self.change & 0x0001 != 0
+    }
+
+    /// USB 2.0 only, port disabled by an error
+    pub fn enable_changed(&self) -> bool {
+        !self.superspeed && self.change & 0x0002 != 0
+    }
+
+    /// USB 2.0 only, resume completed
+    pub fn suspend_changed(&self) -> bool {
+        !self.superspeed && self.change & 0x0004 != 0
+    }
+
+    pub fn over_current_changed(&self) -> bool {
+        self.change & 0x0008 != 0
+    }
+
+    pub fn reset_changed(&self) -> bool {
+        self.change & 0x0010 != 0
+    }
+
+    /// SuperSpeed only
+    pub fn bh_reset_changed(&self) -> bool {
+        self.superspeed && self.change & 0x0020 != 0
+    }
+
+    /// SuperSpeed only
+    pub fn link_state_changed(&self) -> bool {
+        self.superspeed && self.change & 0x0040 != 0
+    }
+
+    /// SuperSpeed only
+    pub fn config_error_changed(&self) -> bool {
+        self.superspeed && self.change & 0x0080 != 0
+    }
+
+    /// Change features that are set and must be cleared to acknowledge them
+    pub fn changes(&self) -> Vec<PortFeature> {
+        let all = [
+            (self.connection_changed(), PortFeature::ConnectionChange),
+            (self.enable_changed(), PortFeature::EnableChange),
+            (self.suspend_changed(), PortFeature::SuspendChange),
+            (self.over_current_changed(), PortFeature::OverCurrentChange),
+            (self.reset_changed(), PortFeature::ResetChange),
+            (self.bh_reset_changed(), PortFeature::BhResetChange),
+            (self.link_state_changed(), PortFeature::LinkStateChange),
+            (self.config_error_changed(), PortFeature::ConfigErrorChange),
+        ];
+        all.iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, feature)| *feature)
+            .collect()
+    }
+}
+
+impl fmt::Display for PortStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "status 0x{:04X} change 0x{:04X}{}{}{}{}",
+            self.status,
+            self.change,
+            if self.powered() { " power" } else { "" },
+            if self.connected() { " connect" } else { "" },
+            if self.enabled() { " enable" } else { "" },
+            if self.over_current() {
+                " over-current"
+            } else {
+                ""
+            },
+        )
+    }
+}
+
+/// A hub opened with UsbCore, UsbIpClient or a Replay
+/// Example:
+/// ```ignore
+/// let mut hub = Hub::new(UsbCore::from_bus_device(1, 3)?)?;
+/// if hub.descriptor().power_switching() == PowerSwitching::PerPort {
+///     hub.power_cycle(2, Duration::from_secs(1))?;
+/// }
+/// println!("{}", hub.port_status(2)?);
+/// ```
+pub struct Hub<U> {
+    usb: U,
+    descriptor: HubDescriptor,
+}
+
+impl<U: UsbCoreDriver + UsbCoreBlocking> Hub<U> {
+    /// Read the USB 2.0 hub descriptor or if that fails the SuperSpeed one
+    pub fn new(mut usb: U) -> io::Result<Self> {
+        let descriptor = match Self::read_descriptor(&mut usb, HUB_DESCRIPTOR) {
+            Ok(descriptor) => descriptor,
+            Err(e) => {
+                log::debug!("No USB 2.0 hub descriptor: {}", e);
+                Self::read_descriptor(&mut usb, SUPERSPEED_HUB_DESCRIPTOR)?
+            }
+        };
+        Ok(Self { usb, descriptor })
+    }
+
+    fn read_descriptor(usb: &mut U, kind: u8) -> io::Result<HubDescriptor> {
+        let request_type = RequestType::new().input().class().device();
+        let data = control_in(
+            usb,
+            request_type,
+            REQUEST_GET_DESCRIPTOR,
+            (kind as u16) << 8,
+            0,
+            71,
+        )?;
+        match HubDescriptor::new(&mut data.iter()) {
+            Some(descriptor) if descriptor.kind == kind => Ok(descriptor),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid hub descriptor {:02X?}", data),
+            )),
+        }
+    }
+
+    pub fn descriptor(&self) -> &HubDescriptor {
+        &self.descriptor
+    }
+
+    pub fn inner(&mut self) -> &mut U {
+        &mut self.usb
+    }
+
+    pub fn into_inner(self) -> U {
+        self.usb
+    }
+
+    fn check_port(&self, port: u8) -> io::Result<()> {
+        if port == 0 || port > self.descriptor.num_ports {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("Hub has ports 1..={}", self.descriptor.num_ports),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn port_status(&mut self, port: u8) -> io::Result<PortStatus> {
+        self.check_port(port)?;
+        let request_type = RequestType::new().input().class().other();
+        let data = control_in(
+            &mut self.usb,
+            request_type,
+            REQUEST_GET_STATUS,
+            0,
+            port as u16,
+            4,
+        )?;
+        match data[..] {
+            [s0, s1, c0, c1] => Ok(PortStatus {
+                status: u16::from_le_bytes([s0, s1]),
+                change: u16::from_le_bytes([c0, c1]),
+                superspeed: self.descriptor.is_superspeed(),
+            }),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "GET_PORT_STATUS did not return 4 bytes",
+            )),
+        }
+    }
+
+    /// selector is put in the upper byte of wIndex as used by PORT_TEST and PORT_INDICATOR
+    fn port_feature(
+        &mut self,
+        request: u8,
+        port: u8,
+        feature: PortFeature,
+        selector: u8,
+    ) -> io::Result<()> {
+        self.check_port(port)?;
+        let request_type = RequestType::new().output().class().other();
+        let ctrl = self.usb.new_control_nodata(
+            request_type.into(),
+            request,
+            feature as u16,
+            (selector as u16) << 8 | port as u16,
+        )?;
+        let ctrl = self
+            .usb
+            .control_async_wait(ctrl, TimeoutMillis(HUB_REQUEST_TIMEOUT_MS))?;
+        if ctrl.status != 0 {
+            return Err(io::Error::from_raw_os_error(-ctrl.status));
+        }
+        Ok(())
+    }
+
+    pub fn set_port_feature(&mut self, port: u8, feature: PortFeature) -> io::Result<()> {
+        self.port_feature(REQUEST_SET_FEATURE, port, feature, 0)
+    }
+
+    pub fn clear_port_feature(&mut self, port: u8, feature: PortFeature) -> io::Result<()> {
+        self.port_feature(REQUEST_CLEAR_FEATURE, port, feature, 0)
+    }
+
+    /// Only switches a single port if power_switching() is PerPort
+    pub fn set_port_power(&mut self, port: u8, on: bool) -> io::Result<()> {
+        if on {
+            self.set_port_feature(port, PortFeature::Power)
+        } else {
+            self.clear_port_feature(port, PortFeature::Power)
+        }
+    }
+
+    /// Turn port power off for off_time then on and wait until power is good
+    pub fn power_cycle(&mut self, port: u8, off_time: Duration) -> io::Result<()> {
+        self.set_port_power(port, false)?;
+        std::thread::sleep(off_time);
+        self.set_port_power(port, true)?;
+        std::thread::sleep(Duration::from_millis(
+            self.descriptor.power_on_to_power_good_ms() as u64,
+        ));
+        Ok(())
+    }
+
+    /// Start a port reset, the kernel hub driver will notice the reset change
+    pub fn reset_port(&mut self, port: u8) -> io::Result<()> {
+        self.set_port_feature(port, PortFeature::Reset)
+    }
+
+    /// Warm reset of a SuperSpeed port
+    pub fn warm_reset_port(&mut self, port: u8) -> io::Result<()> {
+        self.set_port_feature(port, PortFeature::BhReset)
+    }
+
+    /// USB 2.0 ports can only be enabled by a reset
+    pub fn disable_port(&mut self, port: u8) -> io::Result<()> {
+        self.clear_port_feature(port, PortFeature::Enable)
+    }
+
+    pub fn set_port_indicator(&mut self, port: u8, indicator: PortIndicator) -> io::Result<()> {
+        self.port_feature(
+            REQUEST_SET_FEATURE,
+            port,
+            PortFeature::Indicator,
+            indicator as u8,
+        )
+    }
+
+    /// selector 1..=5 (Test_J .. Test_Force_Enable), the hub must be reset to leave test mode.
+    pub fn set_port_test_mode(&mut self, port: u8, selector: u8) -> io::Result<()> {
+        self.port_feature(REQUEST_SET_FEATURE, port, PortFeature::Test, selector)
+    }
+
+    /// Acknowledge all change bits set in status
+    pub fn clear_port_changes(&mut self, port: u8, status: &PortStatus) -> io::Result<()> {
+        for feature in status.changes() {
+            self.clear_port_feature(port, feature)?;
+        }
+        Ok(())
+    }
+}
+
+fn control_in<U: UsbCoreDriver + UsbCoreBlocking>(
+    usb: &mut U,
+    request_type: RequestType,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+) -> io::Result<Vec<u8>> {
+    let ctrl = usb.new_control_in(request_type.into(), request, value, index, length)?;
+    let ctrl = usb.control_async_wait(ctrl, TimeoutMillis(HUB_REQUEST_TIMEOUT_MS))?;
+    if ctrl.status != 0 {
+        return Err(io::Error::from_raw_os_error(-ctrl.status));
+    }
+    Ok(ctrl.as_slice().to_vec())
+}
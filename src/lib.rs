@@ -1,31 +1,63 @@
 use std::time::Duration;
 pub mod descriptors;
 pub mod endpoint;
+#[cfg(target_os = "linux")]
+pub mod hub;
 pub mod os;
+pub mod pcap;
+pub mod record;
+pub mod request;
 pub mod usb_transfer;
+#[cfg(target_os = "linux")]
+pub mod usbip;
 pub use endpoint::{Endpoint, ENDPOINT_IN, ENDPOINT_OUT};
 #[cfg(target_os = "linux")]
+pub use os::linux::capabilities::Capabilities;
+#[cfg(target_os = "linux")]
+#[allow(deprecated)]
 pub use os::linux::constants::*;
 #[cfg(target_os = "linux")]
-pub use os::linux::enumerate::UsbEnumerate;
+pub use os::linux::device_id::DeviceId;
+#[cfg(target_os = "linux")]
+pub use os::linux::disconnect::Disconnected;
+#[cfg(target_os = "linux")]
+pub use os::linux::driver::UsbDriver;
+#[cfg(target_os = "linux")]
+pub use os::linux::enumerate::{EnumerateError, UsbEnumerate};
+#[cfg(target_os = "linux")]
+pub use os::linux::filter::DeviceFilter;
+#[cfg(target_os = "linux")]
+pub use os::linux::handle::{Completion, UsbHandle, UsbLock};
+#[cfg(all(target_os = "linux", feature = "mio"))]
+pub use os::linux::mio::DisconnectEvent;
+#[cfg(target_os = "linux")]
+pub use os::linux::power::PowerControl;
+#[cfg(target_os = "linux")]
+pub use os::linux::stream::{BulkSink, BulkStream};
+#[cfg(target_os = "linux")]
+pub use os::linux::topology::{ConnectionInfo, TopologyNode};
 #[cfg(target_os = "linux")]
-pub use os::linux::usb_device::UsbDevice;
+pub use os::linux::usb_device::{Speed, UsbDevice};
 #[cfg(target_os = "linux")]
 pub use os::linux::usbfs::UsbFs as UsbCore;
-pub use usb_transfer::{BufferSlice, BulkTransfer, ControlTransfer, TransferKind, UsbCoreDriver};
+pub use request::{ControlRequest, Direction, Recipient, RequestType};
+#[allow(deprecated)]
+pub use usb_transfer::BufferSlice;
+pub use usb_transfer::{
+    BulkTransfer, ControlTransfer, TransferBuffer, TransferInfo, TransferKind, UsbCoreBlocking,
+    UsbCoreDriver,
+};
 
 #[derive(Debug, Clone)]
 pub struct TimeoutMillis(u32);
 impl From<u32> for TimeoutMillis {
     fn from(timeout_ms: u32) -> Self {
-        Self { 0: timeout_ms }
+        Self(timeout_ms)
     }
 }
 
 impl From<Duration> for TimeoutMillis {
     fn from(duration: Duration) -> Self {
-        Self {
-            0: duration.as_millis() as u32,
-        }
+        Self(duration.as_millis() as u32)
     }
 }
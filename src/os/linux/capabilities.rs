@@ -0,0 +1,64 @@
+use bitflags::bitflags;
+
+bitflags! {
+    /// USBDEVFS_GET_CAPABILITIES, read once when the device is opened
+    #[derive(Default)]
+    pub struct Capabilities: u32 {
+        const ZERO_PACKET = 0x01;
+        const BULK_CONTINUATION = 0x02;
+        const NO_PACKET_SIZE_LIM = 0x04;
+        const BULK_SCATTER_GATHER = 0x08;
+        const REAP_AFTER_DISCONNECT = 0x10;
+        const MMAP = 0x20;
+        const DROP_PRIVILEGES = 0x40;
+        const CONNINFO_EX = 0x80;
+        const SUSPEND = 0x100;
+    }
+}
+
+impl Capabilities {
+    /// USBFS_URB_FLAGS_ZERO_PACKET on bulk out
+    pub fn zero_packet(&self) -> bool {
+        self.contains(Self::ZERO_PACKET)
+    }
+
+    /// USBFS_URB_FLAGS_BULK_CONTINUATION to chain bulk in URBs
+    pub fn bulk_continuation(&self) -> bool {
+        self.contains(Self::BULK_CONTINUATION)
+    }
+
+    /// Bulk URBs are not limited to 16 KB
+    pub fn no_packet_size_limit(&self) -> bool {
+        self.contains(Self::NO_PACKET_SIZE_LIM)
+    }
+
+    /// Large bulk URBs are split by the kernel using scatter-gather
+    pub fn bulk_scatter_gather(&self) -> bool {
+        self.contains(Self::BULK_SCATTER_GATHER)
+    }
+
+    /// URBs can be reaped after the device is disconnected
+    pub fn reap_after_disconnect(&self) -> bool {
+        self.contains(Self::REAP_AFTER_DISCONNECT)
+    }
+
+    /// Transfer buffers can be mmap'd from usbfs
+    pub fn mmap(&self) -> bool {
+        self.contains(Self::MMAP)
+    }
+
+    /// USBDEVFS_DROP_PRIVILEGES
+    pub fn drop_privileges(&self) -> bool {
+        self.contains(Self::DROP_PRIVILEGES)
+    }
+
+    /// USBDEVFS_CONNINFO_EX
+    pub fn conninfo_ex(&self) -> bool {
+        self.contains(Self::CONNINFO_EX)
+    }
+
+    /// USBDEVFS_FORBID_SUSPEND, ALLOW_SUSPEND and WAIT_FOR_RESUME
+    pub fn suspend(&self) -> bool {
+        self.contains(Self::SUSPEND)
+    }
+}
@@ -0,0 +1,127 @@
+use super::enumerate::UsbEnumerate;
+use super::filter::DeviceFilter;
+use super::usb_device::UsbDevice;
+use crate::UsbCore;
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Identify a physical device across replug, reset and firmware reboot
+/// where bus and device numbers change.
+/// Example:
+/// ```ignore
+/// let id = DeviceId::from_usbcore(&mut usb)?;
+/// // detach to bootloader
+/// drop(usb);
+/// let dfu = id.wait_for_reappear_as(0x0483, 0xdf11, Duration::from_secs(5))?;
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    /// sysfs port path like 1-2.3, empty if unknown
+    pub port_path: String,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Empty if the device has no serial
+    pub serial: String,
+}
+
+impl From<&UsbDevice> for DeviceId {
+    fn from(device: &UsbDevice) -> Self {
+        Self {
+            port_path: device.port_path.clone(),
+            vendor_id: device.device.id_vendor,
+            product_id: device.device.id_product,
+            serial: device.serial.clone(),
+        }
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {:04x}:{:04x}",
+            self.port_path, self.vendor_id, self.product_id
+        )?;
+        if !self.serial.is_empty() {
+            write!(f, " {}", self.serial)?;
+        }
+        Ok(())
+    }
+}
+
+impl DeviceId {
+    pub fn from_usbcore(usb: &mut UsbCore) -> io::Result<Self> {
+        match usb.descriptors() {
+            Some(device) => Ok(Self::from(device)),
+            None => Err(Error::new(ErrorKind::Other, "Could not read descriptors")),
+        }
+    }
+
+    /// Filter matching this device.
+    /// Without port path the serial is the only way to tell identical devices apart.
+    pub fn filter(&self) -> DeviceFilter {
+        self.filter_as(self.vendor_id, self.product_id)
+    }
+
+    /// Filter matching this port but a different VID/PID like a bootloader.
+    /// The serial is only compared if the VID/PID is unchanged.
+    fn filter_as(&self, vendor_id: u16, product_id: u16) -> DeviceFilter {
+        let mut filter = DeviceFilter::new().vid_pid(vendor_id, product_id);
+        if !self.port_path.is_empty() {
+            filter = filter.port_path(&self.port_path);
+        }
+        if !self.serial.is_empty() && vendor_id == self.vendor_id && product_id == self.product_id {
+            filter = filter.serial(&self.serial);
+        }
+        filter
+    }
+
+    pub fn find<'a>(&self, usb: &'a UsbEnumerate) -> Option<&'a UsbDevice> {
+        usb.find(&self.filter()).into_iter().next()
+    }
+
+    /// Open the device if it is currently connected
+    pub fn reopen(&self) -> io::Result<UsbCore> {
+        UsbEnumerate::from_sysfs()?.open_first(&self.filter())
+    }
+
+    /// Poll until the device is connected and can be opened.
+    /// Call after the old handle reported the device gone, otherwise
+    /// the old instance may be found again.
+    pub fn wait_for_reappear(&self, timeout: Duration) -> io::Result<UsbCore> {
+        self.wait_for(&self.filter(), timeout)
+    }
+
+    /// Same as wait_for_reappear but expect the device at the same port
+    /// to come back with another VID/PID, as after a jump to a bootloader.
+    pub fn wait_for_reappear_as(
+        &self,
+        vendor_id: u16,
+        product_id: u16,
+        timeout: Duration,
+    ) -> io::Result<UsbCore> {
+        self.wait_for(&self.filter_as(vendor_id, product_id), timeout)
+    }
+
+    fn wait_for(&self, filter: &DeviceFilter, timeout: Duration) -> io::Result<UsbCore> {
+        let instant = Instant::now();
+        loop {
+            // udev may not have applied permissions yet so retry failed opens
+            match UsbEnumerate::from_sysfs().and_then(|usb| usb.open_first(filter)) {
+                Ok(usb) => return Ok(usb),
+                Err(e) => log::trace!("{} not ready: {}", self, e),
+            }
+            if instant.elapsed() >= timeout {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("{} did not reappear", self),
+                ));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
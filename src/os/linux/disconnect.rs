@@ -0,0 +1,69 @@
+use std::error;
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Error payload once the device is unplugged, every further call on
+/// the UsbCore fails with it.
+/// Example:
+/// ```ignore
+/// match usb.async_response() {
+///     Err(e) if Disconnected::is(&e) => return reopen(),
+///     ...
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Disconnected;
+
+impl fmt::Display for Disconnected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "USB device disconnected")
+    }
+}
+
+impl error::Error for Disconnected {}
+
+impl From<Disconnected> for io::Error {
+    fn from(disconnected: Disconnected) -> Self {
+        Error::new(ErrorKind::NotConnected, disconnected)
+    }
+}
+
+impl Disconnected {
+    /// True for the Disconnected error and for ENODEV or ESHUTDOWN
+    pub fn is(error: &io::Error) -> bool {
+        matches!(
+            error.raw_os_error(),
+            Some(libc::ENODEV) | Some(libc::ESHUTDOWN)
+        ) || error
+            .get_ref()
+            .is_some_and(|inner| inner.downcast_ref::<Disconnected>().is_some())
+    }
+}
+
+/// Turn ENODEV and ESHUTDOWN into Disconnected
+pub(crate) fn map_disconnect(error: io::Error) -> io::Error {
+    if Disconnected::is(&error) {
+        Disconnected.into()
+    } else {
+        error
+    }
+}
+
+/// Disconnected if flag is set
+pub(crate) fn check_connected(flag: &AtomicBool) -> io::Result<()> {
+    if flag.load(Ordering::Relaxed) {
+        return Err(Disconnected.into());
+    }
+    Ok(())
+}
+
+/// Like map_disconnect but also sets flag when the device is gone
+pub(crate) fn mark_disconnect(flag: &AtomicBool, error: io::Error) -> io::Error {
+    let error = map_disconnect(error);
+    if Disconnected::is(&error) {
+        flag.store(true, Ordering::Relaxed);
+    }
+    error
+}
@@ -0,0 +1,187 @@
+use super::usb_device::UsbDevice;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// A kernel USB driver in root/bus/usb/drivers.
+/// Writing bind, unbind and new_id needs root.
+/// Example:
+/// ```ignore
+/// let driver = UsbDriver::new("cdc_acm");
+/// driver.new_id(0x1209, 0x0001)?;
+/// driver.bind("1-2.3:1.0")?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct UsbDriver {
+    name: String,
+    path: PathBuf,
+}
+
+impl fmt::Display for UsbDriver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl UsbDriver {
+    pub fn new(name: &str) -> Self {
+        Self::with_sysfs_root("/sys", name)
+    }
+
+    pub fn with_sysfs_root<P: AsRef<Path>>(root: P, name: &str) -> Self {
+        Self {
+            name: name.into(),
+            path: root.as_ref().join("bus/usb/drivers").join(name),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Bind a device (1-2.3) or interface (1-2.3:1.0) not bound to another driver
+    pub fn bind(&self, name: &str) -> io::Result<()> {
+        fs::write(self.path.join("bind"), name)
+    }
+
+    pub fn unbind(&self, name: &str) -> io::Result<()> {
+        fs::write(self.path.join("unbind"), name)
+    }
+
+    /// Let the driver probe devices with this VID/PID
+    pub fn new_id(&self, vendor_id: u16, product_id: u16) -> io::Result<()> {
+        fs::write(
+            self.path.join("new_id"),
+            format!("{:04x} {:04x}", vendor_id, product_id),
+        )
+    }
+
+    pub fn remove_id(&self, vendor_id: u16, product_id: u16) -> io::Result<()> {
+        fs::write(
+            self.path.join("remove_id"),
+            format!("{:04x} {:04x}", vendor_id, product_id),
+        )
+    }
+
+    /// Devices and interfaces bound to the driver
+    pub fn bound(&self) -> io::Result<Vec<String>> {
+        let mut bound = Vec::new();
+        for entry in fs::read_dir(&self.path)? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            // Bound devices are links named like 1-2.3 or 1-2.3:1.0, also usb1
+            if name.starts_with(|c: char| c.is_ascii_digit()) || name.starts_with("usb") {
+                bound.push(name);
+            }
+        }
+        bound.sort();
+        Ok(bound)
+    }
+}
+
+fn flag(enable: bool) -> &'static str {
+    if enable {
+        "1"
+    } else {
+        "0"
+    }
+}
+
+/// Authorization and driver binding through sysfs
+impl UsbDevice {
+    pub fn authorized(&self) -> io::Result<bool> {
+        Ok(self.read_attribute("authorized")? == "1")
+    }
+
+    pub fn authorize(&self) -> io::Result<()> {
+        self.write_attribute("authorized", "1")
+    }
+
+    /// Unbinds all drivers and unconfigures the device until authorized again
+    pub fn deauthorize(&self) -> io::Result<()> {
+        self.write_attribute("authorized", "0")
+    }
+
+    /// Only present on root hubs, applies to interfaces of new devices on the bus
+    pub fn interface_authorized_default(&self) -> io::Result<bool> {
+        Ok(self.read_attribute("interface_authorized_default")? == "1")
+    }
+
+    pub fn set_interface_authorized_default(&self, authorized: bool) -> io::Result<()> {
+        self.write_attribute("interface_authorized_default", flag(authorized))
+    }
+
+    /// Active bConfigurationValue, 0 if unconfigured
+    pub fn active_configuration(&self) -> io::Result<u8> {
+        let value = self.read_attribute("bConfigurationValue")?;
+        if value.is_empty() {
+            return Ok(0);
+        }
+        value
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", value, e)))
+    }
+
+    /// sysfs name of interface in the active configuration like 1-2.3:1.0
+    pub fn interface_name(&self, interface: u8) -> io::Result<String> {
+        let configuration = self.active_configuration()?;
+        if configuration == 0 {
+            return Err(Error::new(ErrorKind::NotFound, "Device is not configured"));
+        }
+        Ok(format!(
+            "{}:{}.{}",
+            self.port_path, configuration, interface
+        ))
+    }
+
+    fn interface_attribute(&self, interface: u8, name: &str) -> io::Result<String> {
+        Ok(format!("{}/{}", self.interface_name(interface)?, name))
+    }
+
+    pub fn interface_authorized(&self, interface: u8) -> io::Result<bool> {
+        let attribute = self.interface_attribute(interface, "authorized")?;
+        Ok(self.read_attribute(&attribute)? == "1")
+    }
+
+    pub fn authorize_interface(&self, interface: u8) -> io::Result<()> {
+        let attribute = self.interface_attribute(interface, "authorized")?;
+        self.write_attribute(&attribute, "1")
+    }
+
+    /// Unbinds the interface driver and blocks binding until authorized again
+    pub fn deauthorize_interface(&self, interface: u8) -> io::Result<()> {
+        let attribute = self.interface_attribute(interface, "authorized")?;
+        self.write_attribute(&attribute, "0")
+    }
+
+    /// Name of the driver bound to interface, None if not bound
+    pub fn interface_driver(&self, interface: u8) -> io::Result<Option<String>> {
+        let attribute = self.interface_attribute(interface, "driver")?;
+        let path = self
+            .sysfs_path
+            .as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Device has no sysfs path"))?
+            .join(attribute);
+        match fs::read_link(path) {
+            Ok(link) => Ok(link
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Unbind whatever driver is bound to interface
+    pub fn unbind_interface(&self, interface: u8) -> io::Result<()> {
+        let attribute = self.interface_attribute(interface, "driver/unbind")?;
+        self.write_attribute(&attribute, &self.interface_name(interface)?)
+    }
+
+    /// Bind interface to a named driver, the interface must not be bound
+    pub fn bind_interface(&self, interface: u8, driver: &str) -> io::Result<()> {
+        let bind = format!("subsystem/drivers/{}/bind", driver);
+        let attribute = self.interface_attribute(interface, &bind)?;
+        self.write_attribute(&attribute, &self.interface_name(interface)?)
+    }
+}
@@ -1,38 +1,192 @@
-use crate::UsbDevice;
+use super::driver::UsbDriver;
+use super::filter::DeviceFilter;
+use super::topology::TopologyNode;
+use super::usb_device::Speed;
+use crate::{UsbCore, UsbDevice};
 use std::collections::HashMap;
-use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::fs;
+use std::io;
 use std::io::{Error, ErrorKind};
-use sysfs_serde::{SysFs, UsbDevices};
+use std::path::{Path, PathBuf};
 
-impl TryFrom<UsbDevices> for UsbEnumerate {
-    type Error = std::io::Error;
-    fn try_from(sysfs: UsbDevices) -> Result<Self, Self::Error> {
-        let mut en = Self::default();
-        for dev in sysfs.values() {
-            let dev = UsbDevice::from_bytes(dev.descriptors.clone(), |d| {
-                d.product = dev.product.clone();
-                d.manufacturer = dev.manufacturer.clone();
-                d.serial = dev.serial.clone();
-                d.bus_num = dev.bus_num;
-                d.dev_num = dev.dev_num;
-            })?;
-            en.devices
-                .insert(format!("{}-{}", dev.bus_num, dev.dev_num), dev);
-        }
-        Ok(en)
+const SYSFS_ROOT: &str = "/sys";
+const DEVFS_ROOT: &str = "/dev/bus/usb";
+
+/// A device or directory that was found but could not be read
+#[derive(Debug)]
+pub struct EnumerateError {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl fmt::Display for EnumerateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.error)
     }
 }
 
 #[derive(Default)]
 pub struct UsbEnumerate {
-    pub devices: HashMap<String, UsbDevice>,
+    devices: HashMap<String, UsbDevice>,
+    // Devices skipped because descriptors or bus/dev numbers could not be read
+    errors: Vec<EnumerateError>,
+    /// None if enumerated from /dev/bus/usb
+    sysfs_root: Option<PathBuf>,
+}
+
+/// Read a sysfs attribute, None if missing or unreadable
+fn attribute(dir: &Path, name: &str) -> Option<String> {
+    match fs::read(dir.join(name)) {
+        Ok(value) => Some(String::from_utf8_lossy(&value).trim().to_string()),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            log::debug!("Could not read {}/{}: {}", dir.display(), name, e);
+            None
+        }
+    }
+}
+
+fn number_attribute(dir: &Path, name: &str) -> io::Result<u8> {
+    attribute(dir, name)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("{} missing", name)))?
+        .parse()
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", name, e)))
 }
 
 impl UsbEnumerate {
-    pub fn from_sysfs() -> std::io::Result<Self> {
-        SysFs::usb_devices()
-            .map_err(|e| Error::new(ErrorKind::Other, e))?
-            .try_into()
+    /// Enumerate from /sys/bus/usb/devices.
+    /// Falls back to /dev/bus/usb if sysfs is not mounted, as in some containers.
+    pub fn from_sysfs() -> io::Result<Self> {
+        if Path::new(SYSFS_ROOT).join("bus/usb/devices").is_dir() {
+            Self::from_sysfs_root(SYSFS_ROOT)
+        } else {
+            log::debug!("No USB in sysfs, reading {}", DEVFS_ROOT);
+            Self::from_devfs(DEVFS_ROOT)
+        }
+    }
+
+    /// Enumerate from root/bus/usb/devices, root is /sys unless testing
+    /// against a fixture directory.
+    pub fn from_sysfs_root<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let mut en = Self {
+            sysfs_root: Some(root.as_ref().to_path_buf()),
+            ..Default::default()
+        };
+        let dir = root.as_ref().join("bus/usb/devices");
+        for entry in fs::read_dir(&dir)? {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(error) => {
+                    en.errors.push(EnumerateError {
+                        path: dir.clone(),
+                        error,
+                    });
+                    continue;
+                }
+            };
+            let name = entry.file_name().to_string_lossy().to_string();
+            // Interfaces are named like 1-2:1.0
+            if name.contains(':') {
+                continue;
+            }
+            let path = entry.path();
+            match Self::read_sysfs_device(&path, &name) {
+                Ok(dev) => en.insert(dev),
+                Err(error) => en.errors.push(EnumerateError { path, error }),
+            }
+        }
+        Ok(en)
+    }
+
+    fn read_sysfs_device(dir: &Path, name: &str) -> io::Result<UsbDevice> {
+        let descriptors = fs::read(dir.join("descriptors"))?;
+        let bus_num = number_attribute(dir, "busnum")?;
+        let dev_num = number_attribute(dir, "devnum")?;
+        UsbDevice::from_bytes(descriptors, |d| {
+            d.bus_num = bus_num;
+            d.dev_num = dev_num;
+            d.manufacturer = attribute(dir, "manufacturer").unwrap_or_default();
+            d.product = attribute(dir, "product").unwrap_or_default();
+            d.serial = attribute(dir, "serial").unwrap_or_default();
+            d.set_port_path(name);
+            d.speed = attribute(dir, "speed")
+                .map(|speed| Speed::from_sysfs(&speed))
+                .unwrap_or_default();
+            d.max_child = number_attribute(dir, "maxchild").unwrap_or(0);
+            d.sysfs_path = Some(dir.to_path_buf());
+        })
+    }
+
+    /// Enumerate from the usbfs device nodes root/BBB/DDD.
+    /// Only descriptors are available, strings and topology are left empty.
+    pub fn from_devfs<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let mut en = Self::default();
+        let root = root.as_ref();
+        for bus in fs::read_dir(root)? {
+            let bus = match bus {
+                Ok(bus) => bus,
+                Err(error) => {
+                    en.errors.push(EnumerateError {
+                        path: root.to_path_buf(),
+                        error,
+                    });
+                    continue;
+                }
+            };
+            let bus_num = match bus.file_name().to_string_lossy().parse::<u8>() {
+                Ok(bus_num) => bus_num,
+                Err(_) => continue,
+            };
+            let devs = match fs::read_dir(bus.path()) {
+                Ok(devs) => devs,
+                Err(error) => {
+                    en.errors.push(EnumerateError {
+                        path: bus.path(),
+                        error,
+                    });
+                    continue;
+                }
+            };
+            for dev in devs {
+                let dev = match dev {
+                    Ok(dev) => dev,
+                    Err(error) => {
+                        en.errors.push(EnumerateError {
+                            path: bus.path(),
+                            error,
+                        });
+                        continue;
+                    }
+                };
+                let dev_num = match dev.file_name().to_string_lossy().parse::<u8>() {
+                    Ok(dev_num) => dev_num,
+                    Err(_) => continue,
+                };
+                let path = dev.path();
+                let res = fs::read(&path).and_then(|descriptors| {
+                    UsbDevice::from_bytes(descriptors, |d| {
+                        d.bus_num = bus_num;
+                        d.dev_num = dev_num;
+                    })
+                });
+                match res {
+                    Ok(dev) => en.insert(dev),
+                    Err(error) => en.errors.push(EnumerateError { path, error }),
+                }
+            }
+        }
+        Ok(en)
+    }
+
+    fn insert(&mut self, dev: UsbDevice) {
+        self.devices
+            .insert(format!("{}-{}", dev.bus_num, dev.dev_num), dev);
+    }
+
+    /// Devices and directories skipped because they could not be read
+    pub fn errors(&self) -> &[EnumerateError] {
+        &self.errors
     }
 
     pub fn devices(&self) -> &HashMap<String, UsbDevice> {
@@ -42,4 +196,86 @@ impl UsbEnumerate {
     pub fn get_device_from_bus(&self, bus: u8, address: u8) -> Option<&UsbDevice> {
         self.devices.get(&format!("{}-{}", bus, address))
     }
+
+    /// All devices matching filter sorted by bus and device number
+    /// Example:
+    /// ```ignore
+    /// let usb = UsbEnumerate::from_sysfs()?;
+    /// for device in usb.find(&DeviceFilter::new().vid_pid(0x0483, 0x5740)) {
+    ///     println!("{}", device);
+    /// }
+    /// ```
+    pub fn find(&self, filter: &DeviceFilter) -> Vec<&UsbDevice> {
+        let mut devices: Vec<&UsbDevice> = self
+            .devices
+            .values()
+            .filter(|device| filter.matches(device))
+            .collect();
+        devices.sort_by_key(|device| (device.bus_num, device.dev_num));
+        devices
+    }
+
+    /// The only device matching filter.
+    /// Fails with NotFound if none match and InvalidInput if more than one match.
+    pub fn find_one(&self, filter: &DeviceFilter) -> io::Result<&UsbDevice> {
+        let mut devices = self.find(filter);
+        match devices.len() {
+            0 => Err(Error::new(ErrorKind::NotFound, "No device matches filter")),
+            1 => Ok(devices.remove(0)),
+            n => Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} devices matches filter", n),
+            )),
+        }
+    }
+
+    /// Open the first device matching filter that can be opened.
+    pub fn open_first(&self, filter: &DeviceFilter) -> io::Result<UsbCore> {
+        let mut error = Error::new(ErrorKind::NotFound, "No device matches filter");
+        for device in self.find(filter) {
+            match UsbCore::from_device(device) {
+                Ok(usb) => return Ok(usb),
+                Err(e) => {
+                    log::debug!(
+                        "Could not open {}-{}: {}",
+                        device.bus_num,
+                        device.dev_num,
+                        e
+                    );
+                    error = e;
+                }
+            }
+        }
+        Err(error)
+    }
+
+    pub fn get_device_from_port_path(&self, port_path: &str) -> Option<&UsbDevice> {
+        self.devices.values().find(|d| d.port_path == port_path)
+    }
+
+    /// Devices ordered as connected, one tree per root hub.
+    /// Devices whose parent is not enumerated are added as roots.
+    pub fn tree(&self) -> Vec<TopologyNode<'_>> {
+        TopologyNode::build(self.devices.values())
+    }
+
+    /// Kernel driver by name in the same sysfs root as the devices
+    pub fn driver(&self, name: &str) -> UsbDriver {
+        UsbDriver::with_sysfs_root(
+            self.sysfs_root.as_deref().unwrap_or(Path::new(SYSFS_ROOT)),
+            name,
+        )
+    }
+
+    /// All registered USB drivers
+    pub fn drivers(&self) -> io::Result<Vec<UsbDriver>> {
+        let root = self.sysfs_root.as_deref().unwrap_or(Path::new(SYSFS_ROOT));
+        let mut drivers = Vec::new();
+        for entry in fs::read_dir(root.join("bus/usb/drivers"))? {
+            let name = entry?.file_name().to_string_lossy().to_string();
+            drivers.push(UsbDriver::with_sysfs_root(root, &name));
+        }
+        drivers.sort_by(|a, b| a.name().cmp(b.name()));
+        Ok(drivers)
+    }
 }
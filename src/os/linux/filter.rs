@@ -0,0 +1,195 @@
+use super::usb_device::{Speed, UsbDevice};
+use crate::descriptors::interface::Interface;
+
+/// Select devices from UsbEnumerate, all given criteria must match.
+/// Interface criteria must all match the same interface in any configuration.
+/// Example:
+/// ```ignore
+/// let filter = DeviceFilter::new()
+///     .vendor_id(0x0483)
+///     .product("*Virtual ComPort*")
+///     .interface_class(0x02);
+/// let usb = UsbEnumerate::from_sysfs()?.open_first(&filter)?;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DeviceFilter {
+    vendor_id: Option<u16>,
+    product_id: Option<u16>,
+    serial: Option<String>,
+    manufacturer: Option<String>,
+    product: Option<String>,
+    device_class: ClassFilter,
+    interface_class: ClassFilter,
+    bus: Option<u8>,
+    port_path: Option<String>,
+    speed: Option<Speed>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ClassFilter {
+    class: Option<u8>,
+    subclass: Option<u8>,
+    protocol: Option<u8>,
+}
+
+impl ClassFilter {
+    fn is_empty(&self) -> bool {
+        self.class.is_none() && self.subclass.is_none() && self.protocol.is_none()
+    }
+
+    fn matches(&self, class: u8, subclass: u8, protocol: u8) -> bool {
+        self.class.map_or(true, |c| c == class)
+            && self.subclass.map_or(true, |s| s == subclass)
+            && self.protocol.map_or(true, |p| p == protocol)
+    }
+
+    fn matches_interface(&self, iface: &Interface) -> bool {
+        self.matches(
+            iface.interface_class,
+            iface.interface_sub_class,
+            iface.interface_protocol,
+        )
+    }
+}
+
+impl DeviceFilter {
+    /// Filter matching any device
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn vendor_id(mut self, vendor_id: u16) -> Self {
+        self.vendor_id = Some(vendor_id);
+        self
+    }
+
+    pub fn product_id(mut self, product_id: u16) -> Self {
+        self.product_id = Some(product_id);
+        self
+    }
+
+    pub fn vid_pid(self, vendor_id: u16, product_id: u16) -> Self {
+        self.vendor_id(vendor_id).product_id(product_id)
+    }
+
+    /// Exact serial number
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.into());
+        self
+    }
+
+    /// Manufacturer string, glob with * and ?
+    pub fn manufacturer(mut self, pattern: &str) -> Self {
+        self.manufacturer = Some(pattern.into());
+        self
+    }
+
+    /// Product string, glob with * and ?
+    pub fn product(mut self, pattern: &str) -> Self {
+        self.product = Some(pattern.into());
+        self
+    }
+
+    pub fn device_class(mut self, class: u8) -> Self {
+        self.device_class.class = Some(class);
+        self
+    }
+
+    pub fn device_subclass(mut self, subclass: u8) -> Self {
+        self.device_class.subclass = Some(subclass);
+        self
+    }
+
+    pub fn device_protocol(mut self, protocol: u8) -> Self {
+        self.device_class.protocol = Some(protocol);
+        self
+    }
+
+    pub fn interface_class(mut self, class: u8) -> Self {
+        self.interface_class.class = Some(class);
+        self
+    }
+
+    pub fn interface_subclass(mut self, subclass: u8) -> Self {
+        self.interface_class.subclass = Some(subclass);
+        self
+    }
+
+    pub fn interface_protocol(mut self, protocol: u8) -> Self {
+        self.interface_class.protocol = Some(protocol);
+        self
+    }
+
+    pub fn bus(mut self, bus: u8) -> Self {
+        self.bus = Some(bus);
+        self
+    }
+
+    /// sysfs port path like 1-2.3
+    pub fn port_path(mut self, port_path: &str) -> Self {
+        self.port_path = Some(port_path.into());
+        self
+    }
+
+    pub fn speed(mut self, speed: Speed) -> Self {
+        self.speed = Some(speed);
+        self
+    }
+
+    pub fn matches(&self, usb: &UsbDevice) -> bool {
+        let device = &usb.device;
+        self.vendor_id.map_or(true, |v| v == device.id_vendor)
+            && self.product_id.map_or(true, |p| p == device.id_product)
+            && self.serial.as_ref().map_or(true, |s| *s == usb.serial)
+            && self
+                .manufacturer
+                .as_ref()
+                .map_or(true, |m| glob(m, &usb.manufacturer))
+            && self
+                .product
+                .as_ref()
+                .map_or(true, |p| glob(p, &usb.product))
+            && self.device_class.matches(
+                device.device_class,
+                device.device_sub_class,
+                device.device_protocol,
+            )
+            && (self.interface_class.is_empty()
+                || device
+                    .configurations
+                    .iter()
+                    .flat_map(|c| c.interfaces.iter())
+                    .any(|i| self.interface_class.matches_interface(i)))
+            && self.bus.map_or(true, |b| b == usb.bus_num)
+            && self
+                .port_path
+                .as_ref()
+                .map_or(true, |p| *p == usb.port_path)
+            && self.speed.map_or(true, |s| s == usb.speed)
+    }
+}
+
+/// Match text against pattern where * is any sequence and ? any character
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of last * and the text position it is currently matching from
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
@@ -0,0 +1,575 @@
+use super::disconnect::Disconnected;
+use super::usbfs::{bulk_transfer, control_transfer, UsbFs, MAX_URB_SIZE};
+use crate::endpoint::Endpoint;
+use crate::request::ControlRequest;
+use crate::usb_transfer::*;
+use crate::TimeoutMillis;
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+type Senders = HashMap<usize, Sender<io::Result<TransferKind>>>;
+
+struct Shared {
+    usb: Mutex<UsbFs>,
+    fd: RawFd,
+    // Completion channels by transfer buffer address, None when the reaper has stopped
+    pending: Mutex<Option<Senders>>,
+    stop: AtomicBool,
+    // Shared with UsbFs, lets the lock-free transfers report Disconnected
+    disconnected: Arc<AtomicBool>,
+    wake: OwnedFd,
+    // Readable when a transfer deadline has passed
+    timer: RawFd,
+}
+
+impl Shared {
+    fn usb(&self) -> MutexGuard<'_, UsbFs> {
+        self.usb.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn pending(&self) -> MutexGuard<'_, Option<Senders>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reap everything completed and hand it to the waiting channels
+    fn dispatch(&self) -> io::Result<()> {
+        let (result, transfers) = {
+            let mut usb = self.usb();
+            let result = usb.async_response_all();
+            (result, usb.collect_responses())
+        };
+        self.deliver(transfers);
+        result.map(|_| ())
+    }
+
+    fn deliver(&self, transfers: Vec<TransferKind>) {
+        if transfers.is_empty() {
+            return;
+        }
+        let mut pending = self.pending();
+        for transfer in transfers {
+            let buffer = match &transfer {
+                TransferKind::Bulk(bulk) => bulk.buffer_address(),
+                TransferKind::Control(control) => control.buffer_address(),
+                TransferKind::Invalid(_) => continue,
+            };
+            // Transfers nobody waits for are dropped here
+            if let Some(sender) = pending.as_mut().and_then(|p| p.remove(&buffer)) {
+                let _ = sender.send(Ok(transfer));
+            }
+        }
+    }
+
+    /// Stop taking transfers and fail everything still waiting
+    fn close(&self, error: Option<io::Error>) {
+        let senders = self.pending().take().unwrap_or_default();
+        if let Some(error) = error {
+            for sender in senders.into_values() {
+                let _ = sender.send(Err(copy_error(&error)));
+            }
+        }
+    }
+
+    fn reap(&self) {
+        let mut fds = [
+            libc::pollfd {
+                fd: self.fd,
+                events: libc::POLLOUT,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.wake.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.timer,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        loop {
+            fds.iter_mut().for_each(|fd| fd.revents = 0);
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                return self.close(Some(error));
+            }
+            if self.stop.load(Ordering::Acquire) {
+                return;
+            }
+            if let Err(error) = self.dispatch() {
+                return self.close(Some(error));
+            }
+            if fds[0].revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+                self.disconnected.store(true, Ordering::Relaxed);
+                return self.close(Some(Disconnected.into()));
+            }
+        }
+    }
+}
+
+fn copy_error(error: &io::Error) -> io::Error {
+    if Disconnected::is(error) {
+        return Disconnected.into();
+    }
+    match error.raw_os_error() {
+        Some(code) => io::Error::from_raw_os_error(code),
+        None => Error::new(error.kind(), error.to_string()),
+    }
+}
+
+/// Stops the reaper thread when the last UsbHandle is dropped
+struct Reaper {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Release);
+        let one: u64 = 1;
+        unsafe {
+            libc::write(
+                self.shared.wake.as_raw_fd(),
+                &one as *const u64 as *const libc::c_void,
+                8,
+            );
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.shared.close(None);
+    }
+}
+
+/// Cloneable UsbCore that can be shared between threads.
+/// A reaper thread reaps completed URBs and passes each one to the
+/// Completion returned when it was submitted.
+/// Example:
+/// ```ignore
+/// let usb = UsbHandle::new(UsbCore::from_device(&device)?)?;
+/// usb.lock().claim_interface(0)?;
+/// let reader = usb.clone();
+/// std::thread::spawn(move || loop {
+///     let bulk = reader.clone().new_bulk_in(1, 64)?;
+///     let bulk = reader.submit_bulk(bulk)?.wait()?;
+///     println!("{:?}", bulk.as_slice());
+/// });
+/// usb.bulk_write(1, b"HELLO", 1000.into())?;
+/// ```
+#[derive(Clone)]
+pub struct UsbHandle {
+    shared: Arc<Shared>,
+    _reaper: Arc<Reaper>,
+}
+
+impl UsbHandle {
+    pub fn new(mut usb: UsbFs) -> io::Result<Self> {
+        let timer = usb.timer_fd()?;
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if wake < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let shared = Arc::new(Shared {
+            fd: usb.handle().as_raw_fd(),
+            disconnected: usb.disconnected_flag(),
+            usb: Mutex::new(usb),
+            pending: Mutex::new(Some(HashMap::new())),
+            stop: AtomicBool::new(false),
+            wake: unsafe { OwnedFd::from_raw_fd(wake) },
+            timer,
+        });
+        let reaper = shared.clone();
+        let thread = std::thread::Builder::new()
+            .name("usbapi-reaper".into())
+            .spawn(move || reaper.reap())?;
+        Ok(Self {
+            _reaper: Arc::new(Reaper {
+                shared: shared.clone(),
+                thread: Some(thread),
+            }),
+            shared,
+        })
+    }
+
+    /// Lock the device for everything not done through the handle like
+    /// claim_interface(). Transfers must be submitted through the handle,
+    /// async_response() through the lock takes them from the reaper.
+    pub fn lock(&self) -> UsbLock<'_> {
+        UsbLock {
+            usb: self.shared.usb(),
+            shared: &self.shared,
+        }
+    }
+
+    fn submit<T>(
+        &self,
+        buffer: usize,
+        convert: fn(TransferKind) -> io::Result<T>,
+        submit: impl FnOnce(&mut UsbFs) -> io::Result<i32>,
+    ) -> io::Result<Completion<T>> {
+        self.try_submit(buffer, convert, (), |usb, ()| {
+            submit(usb).map_err(|e| (e, ()))
+        })
+        .map_err(|(e, ())| e)
+    }
+
+    /// Like submit() but transfer is given back with the error when it
+    /// could not be submitted
+    fn try_submit<T, R>(
+        &self,
+        buffer: usize,
+        convert: fn(TransferKind) -> io::Result<T>,
+        transfer: R,
+        submit: impl FnOnce(&mut UsbFs, R) -> Result<i32, (io::Error, R)>,
+    ) -> Result<Completion<T>, (io::Error, R)> {
+        let (sender, receiver) = mpsc::channel();
+        match self.shared.pending().as_mut() {
+            Some(pending) => pending.insert(buffer, sender),
+            None => {
+                let error = Error::new(ErrorKind::BrokenPipe, "Reaper thread has stopped");
+                return Err((error, transfer));
+            }
+        };
+        if let Err(e) = submit(&mut self.shared.usb(), transfer) {
+            if let Some(pending) = self.shared.pending().as_mut() {
+                pending.remove(&buffer);
+            }
+            return Err(e);
+        }
+        Ok(Completion {
+            receiver,
+            shared: self.shared.clone(),
+            buffer,
+            convert,
+        })
+    }
+
+    /// The transfer completes with status -ETIMEDOUT if not done within
+    /// timeout, 0 never expires.
+    pub fn submit_bulk_timeout(
+        &self,
+        bulk: BulkTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<Completion<BulkTransfer>> {
+        self.submit(bulk.buffer_address(), into_bulk, |usb| {
+            usb.submit_bulk_timeout(bulk, timeout)
+        })
+    }
+
+    pub fn submit_control_timeout(
+        &self,
+        control: ControlTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<Completion<ControlTransfer>> {
+        self.submit(control.buffer_address(), into_control, |usb| {
+            usb.submit_control_timeout(control, timeout)
+        })
+    }
+
+    pub fn submit_bulk(&self, bulk: BulkTransfer) -> io::Result<Completion<BulkTransfer>> {
+        self.submit(bulk.buffer_address(), into_bulk, |usb| {
+            usb.submit_bulk(bulk)
+        })
+    }
+
+    /// submit_bulk() that gives the transfer back with the error when it
+    /// could not be submitted
+    pub(crate) fn try_submit_bulk(
+        &self,
+        bulk: BulkTransfer,
+    ) -> Result<Completion<BulkTransfer>, (io::Error, BulkTransfer)> {
+        self.try_submit(bulk.buffer_address(), into_bulk, bulk, |usb, bulk| {
+            usb.try_submit_bulk(bulk)
+        })
+    }
+
+    pub fn submit_control(
+        &self,
+        control: ControlTransfer,
+    ) -> io::Result<Completion<ControlTransfer>> {
+        self.submit(control.buffer_address(), into_control, |usb| {
+            usb.submit_control(control)
+        })
+    }
+
+    /// Blocked bulk read, does not hold the lock while waiting unless
+    /// mem is larger than one URB and has to be split.
+    pub fn bulk_read(&self, ep: u8, mem: &mut [u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        if mem.len() > MAX_URB_SIZE {
+            return self.lock().bulk_read(ep, mem, timeout);
+        }
+        bulk_transfer(
+            self.shared.fd,
+            &self.shared.disconnected,
+            Endpoint::bulk_in(ep).into(),
+            mem.as_mut_ptr() as *mut libc::c_void,
+            mem.len() as u32,
+            timeout,
+        )
+    }
+
+    /// Blocked bulk write, does not hold the lock while waiting unless
+    /// mem is larger than one URB and has to be split.
+    pub fn bulk_write(&self, ep: u8, mem: &[u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        if mem.len() > MAX_URB_SIZE {
+            return self.lock().bulk_write(ep, mem, timeout);
+        }
+        bulk_transfer(
+            self.shared.fd,
+            &self.shared.disconnected,
+            ep & 0x7F,
+            mem.as_ptr() as *mut libc::c_void,
+            mem.len() as u32,
+            timeout,
+        )
+    }
+
+    /// Blocked control in without holding the lock, see UsbFs::control_in()
+    pub fn control_in(
+        &self,
+        mut req: ControlRequest,
+        data: &mut [u8],
+        timeout: TimeoutMillis,
+    ) -> io::Result<u32> {
+        req.request_type = req.request_type.input();
+        control_transfer(
+            self.shared.fd,
+            &self.shared.disconnected,
+            req,
+            data.as_mut_ptr() as *mut libc::c_void,
+            data.len(),
+            timeout,
+        )
+    }
+
+    /// Blocked control out without holding the lock, see UsbFs::control_out()
+    pub fn control_out(
+        &self,
+        mut req: ControlRequest,
+        data: &[u8],
+        timeout: TimeoutMillis,
+    ) -> io::Result<u32> {
+        req.request_type = req.request_type.output();
+        control_transfer(
+            self.shared.fd,
+            &self.shared.disconnected,
+            req,
+            data.as_ptr() as *mut libc::c_void,
+            data.len(),
+            timeout,
+        )
+    }
+}
+
+/// Locked UsbFs from UsbHandle::lock().
+/// Blocking calls like control_async_wait() reap transfers submitted by
+/// other threads while locked, those are given to their Completion when
+/// the lock is dropped.
+pub struct UsbLock<'a> {
+    usb: MutexGuard<'a, UsbFs>,
+    shared: &'a Shared,
+}
+
+impl Deref for UsbLock<'_> {
+    type Target = UsbFs;
+    fn deref(&self) -> &UsbFs {
+        &self.usb
+    }
+}
+
+impl DerefMut for UsbLock<'_> {
+    fn deref_mut(&mut self) -> &mut UsbFs {
+        &mut self.usb
+    }
+}
+
+impl Drop for UsbLock<'_> {
+    fn drop(&mut self) {
+        let transfers = self.usb.collect_responses();
+        self.shared.deliver(transfers);
+    }
+}
+
+fn into_bulk(transfer: TransferKind) -> io::Result<BulkTransfer> {
+    match transfer {
+        TransferKind::Bulk(bulk) => Ok(bulk),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Expected a bulk transfer",
+        )),
+    }
+}
+
+fn into_control(transfer: TransferKind) -> io::Result<ControlTransfer> {
+    match transfer {
+        TransferKind::Control(control) => Ok(control),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            "Expected a control transfer",
+        )),
+    }
+}
+
+impl UsbCoreDriver for UsbHandle {
+    fn new_bulk_in(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
+        self.lock().new_bulk_in(ep, buffer_capacity)
+    }
+
+    fn new_bulk_out(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
+        self.lock().new_bulk_out(ep, buffer_capacity)
+    }
+
+    fn new_control(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> io::Result<ControlTransfer> {
+        self.lock()
+            .new_control(request_type, request, value, index, length)
+    }
+}
+
+impl UsbCoreBlocking for UsbHandle {
+    fn bulk_read(&mut self, ep: u8, mem: &mut [u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        UsbHandle::bulk_read(self, ep, mem, timeout)
+    }
+
+    fn bulk_write(&mut self, ep: u8, mem: &[u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        UsbHandle::bulk_write(self, ep, mem, timeout)
+    }
+
+    fn control_async_wait(
+        &mut self,
+        ctrl: ControlTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<ControlTransfer> {
+        let control = self.submit_control_timeout(ctrl, timeout)?.wait()?;
+        if control.status == -libc::ETIMEDOUT {
+            return Err(Error::new(ErrorKind::TimedOut, ""));
+        }
+        Ok(control)
+    }
+}
+
+/// A submitted transfer, given back by the reaper thread when completed.
+/// Check status on the returned transfer, it is negative errno on failure.
+pub struct Completion<T> {
+    receiver: Receiver<io::Result<TransferKind>>,
+    shared: Arc<Shared>,
+    buffer: usize,
+    convert: fn(TransferKind) -> io::Result<T>,
+}
+
+fn closed() -> io::Error {
+    Error::new(
+        ErrorKind::BrokenPipe,
+        "Device handle closed before the transfer completed",
+    )
+}
+
+impl<T> Completion<T> {
+    pub fn wait(self) -> io::Result<T> {
+        (self.convert)(self.receiver.recv().map_err(|_| closed())??)
+    }
+
+    /// Wait up to timeout, TimedOut error if not completed yet.
+    /// The transfer is still submitted so wait again or cancel().
+    pub fn wait_timeout(&self, timeout: Duration) -> io::Result<T> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(transfer) => (self.convert)(transfer?),
+            Err(RecvTimeoutError::Timeout) => Err(Error::new(ErrorKind::TimedOut, "")),
+            Err(RecvTimeoutError::Disconnected) => Err(closed()),
+        }
+    }
+
+    /// WouldBlock error if not completed yet
+    pub fn try_wait(&self) -> io::Result<T> {
+        match self.receiver.try_recv() {
+            Ok(transfer) => (self.convert)(transfer?),
+            Err(TryRecvError::Empty) => Err(Error::new(ErrorKind::WouldBlock, "")),
+            Err(TryRecvError::Disconnected) => Err(closed()),
+        }
+    }
+
+    /// Discard the URB, it completes with a negative status
+    pub fn cancel(&self) -> io::Result<()> {
+        self.shared.usb().discard_urb(self.buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::thread;
+
+    /// Handle without a reaper thread, only UsbLock can deliver transfers
+    fn handle() -> UsbHandle {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        let usb = UsbFs::from_file(file).unwrap();
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        assert!(wake >= 0);
+        let shared = Arc::new(Shared {
+            fd: usb.handle().as_raw_fd(),
+            disconnected: usb.disconnected_flag(),
+            usb: Mutex::new(usb),
+            pending: Mutex::new(Some(HashMap::new())),
+            stop: AtomicBool::new(false),
+            wake: unsafe { OwnedFd::from_raw_fd(wake) },
+            timer: -1,
+        });
+        UsbHandle {
+            _reaper: Arc::new(Reaper {
+                shared: shared.clone(),
+                thread: None,
+            }),
+            shared,
+        }
+    }
+
+    #[test]
+    fn transfer_reaped_under_lock_reaches_other_thread() {
+        let usb = handle();
+        let bulk = BulkTransfer::input(1, TransferBuffer::heap(64).unwrap());
+        let buffer = bulk.buffer_address();
+        let (sender, receiver) = mpsc::channel();
+        usb.shared
+            .pending()
+            .as_mut()
+            .unwrap()
+            .insert(buffer, sender);
+        let completion = Completion {
+            receiver,
+            shared: usb.shared.clone(),
+            buffer,
+            convert: into_bulk,
+        };
+        let waiter = thread::spawn(move || {
+            completion
+                .wait_timeout(Duration::from_secs(5))
+                .map(|bulk| bulk.buffer_address())
+        });
+        // Like reap_own() in a split bulk_read on this thread
+        usb.lock().push_response(TransferKind::Bulk(bulk));
+        assert_eq!(waiter.join().unwrap().unwrap(), buffer);
+    }
+}
@@ -4,6 +4,50 @@ use mio::unix::SourceFd;
 use mio::{Interest, Registry, Token};
 use std::io;
 use std::os::unix::io::AsRawFd;
+
+/// Reports an event only when the device is disconnected, register it
+/// with Interest::READABLE next to the UsbCore.
+/// Example:
+/// ```ignore
+/// let mut disconnect = usb.disconnect_event()?;
+/// poll.registry().register(&mut disconnect, DISCONNECT, Interest::READABLE)?;
+/// ```
+pub struct DisconnectEvent {
+    // usbfs never sets POLLIN, epoll always reports POLLERR and POLLHUP
+    handle: std::fs::File,
+}
+
+impl UsbFs {
+    pub fn disconnect_event(&self) -> io::Result<DisconnectEvent> {
+        Ok(DisconnectEvent {
+            handle: self.handle.try_clone()?,
+        })
+    }
+}
+
+impl Source for DisconnectEvent {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.handle.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.handle.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.handle.as_raw_fd()).deregister(registry)
+    }
+}
 impl Source for UsbFs {
     fn register(
         &mut self,
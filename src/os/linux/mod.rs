@@ -1,7 +1,16 @@
+pub mod capabilities;
 pub mod constants;
+pub mod device_id;
+pub mod disconnect;
+pub mod driver;
 pub mod enumerate;
+pub mod filter;
+pub mod handle;
 #[cfg(feature = "mio")]
 pub mod mio;
+pub mod power;
+pub mod stream;
+pub mod topology;
 pub mod usb_device;
 pub mod usbfs;
 mod usbfsurb;
@@ -0,0 +1,110 @@
+use super::usb_device::UsbDevice;
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+/// power/control, Auto allows runtime suspend and On keeps the device active
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerControl {
+    Auto,
+    On,
+}
+
+impl FromStr for PowerControl {
+    type Err = io::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(PowerControl::Auto),
+            "on" => Ok(PowerControl::On),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown power control '{}'", s),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for PowerControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerControl::Auto => write!(f, "auto"),
+            PowerControl::On => write!(f, "on"),
+        }
+    }
+}
+
+fn parse_bool(name: &str, value: &str) -> io::Result<bool> {
+    match value {
+        "1" | "enabled" => Ok(true),
+        "0" | "disabled" => Ok(false),
+        _ => Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Unexpected {} value '{}'", name, value),
+        )),
+    }
+}
+
+/// Runtime power management through the sysfs power attributes.
+/// Writing needs root or a udev rule giving access to the attributes.
+impl UsbDevice {
+    pub fn power_control(&self) -> io::Result<PowerControl> {
+        self.read_attribute("power/control")?.parse()
+    }
+
+    pub fn set_power_control(&self, control: PowerControl) -> io::Result<()> {
+        self.write_attribute("power/control", &control.to_string())
+    }
+
+    /// Idle time before autosuspend, negative means never
+    pub fn autosuspend_delay_ms(&self) -> io::Result<i32> {
+        let value = self.read_attribute("power/autosuspend_delay_ms")?;
+        value
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("{}: {}", value, e)))
+    }
+
+    pub fn set_autosuspend_delay_ms(&self, delay_ms: i32) -> io::Result<()> {
+        self.write_attribute("power/autosuspend_delay_ms", &delay_ms.to_string())
+    }
+
+    /// Remote wakeup, None if the device can not wake the system
+    pub fn wakeup(&self) -> io::Result<Option<bool>> {
+        let value = self.read_attribute("power/wakeup")?;
+        if value.is_empty() {
+            return Ok(None);
+        }
+        parse_bool("wakeup", &value).map(Some)
+    }
+
+    pub fn set_wakeup(&self, enable: bool) -> io::Result<()> {
+        let value = if enable { "enabled" } else { "disabled" };
+        self.write_attribute("power/wakeup", value)
+    }
+
+    /// Keep the device across system suspend even if the bus lost power
+    pub fn persist(&self) -> io::Result<bool> {
+        parse_bool("persist", &self.read_attribute("power/persist")?)
+    }
+
+    pub fn set_persist(&self, enable: bool) -> io::Result<()> {
+        self.write_attribute("power/persist", if enable { "1" } else { "0" })
+    }
+
+    /// USB 2.0 Link Power Management, only present on LPM capable devices
+    pub fn usb2_hardware_lpm(&self) -> io::Result<bool> {
+        parse_bool(
+            "usb2_hardware_lpm",
+            &self.read_attribute("power/usb2_hardware_lpm")?,
+        )
+    }
+
+    pub fn set_usb2_hardware_lpm(&self, enable: bool) -> io::Result<()> {
+        self.write_attribute("power/usb2_hardware_lpm", if enable { "1" } else { "0" })
+    }
+
+    /// active, suspended, suspending, resuming or unsupported
+    pub fn runtime_status(&self) -> io::Result<String> {
+        self.read_attribute("power/runtime_status")
+    }
+}
@@ -0,0 +1,326 @@
+use super::handle::{Completion, UsbHandle};
+use crate::usb_transfer::*;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::time::Duration;
+
+/// Longest BulkSink drop waits for each transfer in flight
+const DROP_TIMEOUT: Duration = Duration::from_secs(1);
+
+fn status_error(bulk: &BulkTransfer) -> Option<io::Error> {
+    if bulk.status != 0 {
+        Some(io::Error::from_raw_os_error(-bulk.status))
+    } else {
+        None
+    }
+}
+
+/// Submitted transfers in submit order
+struct Queue {
+    usb: UsbHandle,
+    in_flight: VecDeque<Completion<BulkTransfer>>,
+    timeout: Option<Duration>,
+}
+
+impl Queue {
+    fn new(usb: &UsbHandle) -> Self {
+        Self {
+            usb: usb.clone(),
+            in_flight: VecDeque::new(),
+            timeout: None,
+        }
+    }
+
+    /// bulk is given back with the error if it could not be submitted
+    fn submit(&mut self, mut bulk: BulkTransfer) -> Result<(), (io::Error, BulkTransfer)> {
+        bulk.actual_length = 0;
+        let completion = self.usb.try_submit_bulk(bulk)?;
+        self.in_flight.push_back(completion);
+        Ok(())
+    }
+
+    /// Oldest transfer when completed, None if nothing is in flight
+    fn wait_oldest(&mut self) -> io::Result<Option<BulkTransfer>> {
+        let result = match (self.in_flight.front(), self.timeout) {
+            (None, _) => return Ok(None),
+            (Some(completion), Some(timeout)) => match completion.wait_timeout(timeout) {
+                Err(e) if e.kind() == ErrorKind::TimedOut => return Err(e),
+                result => {
+                    self.in_flight.pop_front();
+                    result
+                }
+            },
+            (Some(_), None) => self.in_flight.pop_front().map(Completion::wait).unwrap(),
+        };
+        result.map(Some)
+    }
+
+    fn cancel_all(&mut self) {
+        for completion in self.in_flight.drain(..) {
+            let _ = completion.cancel();
+        }
+    }
+}
+
+/// Keeps a number of bulk IN transfers in flight and resubmits them as
+/// they are read, data is given back in the order it was received.
+/// transfer_size should be a multiple of wMaxPacketSize.
+/// Example:
+/// ```ignore
+/// let usb = UsbHandle::new(UsbCore::from_device(&device)?)?;
+/// usb.lock().claim_interface(0)?;
+/// let mut stream = BulkStream::new(&usb, 2, 8, 16384)?;
+/// let mut samples = vec![0u8; 1 << 20];
+/// stream.read_exact(&mut samples)?;
+/// ```
+pub struct BulkStream {
+    queue: Queue,
+    current: Option<BulkTransfer>,
+    position: usize,
+}
+
+impl BulkStream {
+    /// Submit transfers URBs of transfer_size bytes on IN endpoint ep
+    pub fn new(
+        usb: &UsbHandle,
+        ep: u8,
+        transfers: usize,
+        transfer_size: usize,
+    ) -> io::Result<Self> {
+        let mut queue = Queue::new(usb);
+        let mut usb = usb.clone();
+        for _ in 0..transfers {
+            let submitted = usb
+                .new_bulk_in(ep, transfer_size)
+                .and_then(|bulk| queue.submit(bulk).map_err(|(e, _)| e));
+            if let Err(e) = submitted {
+                queue.cancel_all();
+                return Err(e);
+            }
+        }
+        Ok(Self {
+            queue,
+            current: None,
+            position: 0,
+        })
+    }
+
+    /// Give up waiting for data after timeout, None blocks (default)
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.queue.timeout = timeout;
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.queue.in_flight.len()
+    }
+
+    /// Next transfer with data, the previous one is resubmitted.
+    /// Transfers failed with a status are resubmitted and reported as error.
+    fn next_transfer(&mut self) -> io::Result<Option<&BulkTransfer>> {
+        if let Some(bulk) = self.current.take() {
+            self.resubmit(bulk)?;
+        }
+        loop {
+            let bulk = match self.queue.wait_oldest()? {
+                Some(bulk) => bulk,
+                None => return Ok(None),
+            };
+            if let Some(error) = status_error(&bulk) {
+                self.resubmit(bulk)?;
+                return Err(error);
+            }
+            if bulk.is_empty() {
+                // Zero length packet, nothing to give back
+                self.resubmit(bulk)?;
+                continue;
+            }
+            self.position = 0;
+            return Ok(Some(self.current.insert(bulk)));
+        }
+    }
+
+    /// Submit bulk again, if that fails it is kept empty as current and
+    /// submitted on the next read
+    fn resubmit(&mut self, bulk: BulkTransfer) -> io::Result<()> {
+        self.queue.submit(bulk).map_err(|(error, bulk)| {
+            self.position = 0;
+            self.current = Some(bulk);
+            error
+        })
+    }
+
+    fn remaining(&self) -> &[u8] {
+        match &self.current {
+            Some(bulk) => &bulk[self.position..],
+            None => &[],
+        }
+    }
+}
+
+impl Read for BulkStream {
+    /// Returns 0 only when no transfers are left in flight
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining().is_empty() && self.next_transfer()?.is_none() {
+            return Ok(0);
+        }
+        let remaining = self.remaining();
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+impl Iterator for BulkStream {
+    type Item = io::Result<Vec<u8>>;
+
+    /// Data of one completed transfer
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.remaining().is_empty() {
+            let data = self.remaining().to_vec();
+            self.position += data.len();
+            return Some(Ok(data));
+        }
+        match self.next_transfer() {
+            Ok(Some(bulk)) => {
+                let data = bulk.to_vec();
+                self.position = data.len();
+                Some(Ok(data))
+            }
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl Drop for BulkStream {
+    fn drop(&mut self) {
+        self.queue.cancel_all();
+    }
+}
+
+/// Bulk OUT counterpart of BulkStream, written data is sent in transfers
+/// of transfer_size bytes with up to transfers URBs in flight.
+/// flush() sends a partly filled transfer and waits for all of them.
+/// Drop flushes too but waits at most one second per transfer, what is
+/// not sent by then is cancelled.
+pub struct BulkSink {
+    queue: Queue,
+    free: Vec<BulkTransfer>,
+    current: Option<BulkTransfer>,
+}
+
+impl BulkSink {
+    pub fn new(
+        usb: &UsbHandle,
+        ep: u8,
+        transfers: usize,
+        transfer_size: usize,
+    ) -> io::Result<Self> {
+        let mut free = Vec::with_capacity(transfers);
+        let mut usb_driver = usb.clone();
+        for _ in 0..transfers {
+            free.push(usb_driver.new_bulk_out(ep, transfer_size)?);
+        }
+        Ok(Self {
+            queue: Queue::new(usb),
+            free,
+            current: None,
+        })
+    }
+
+    /// Give up waiting for a free transfer after timeout, None blocks (default)
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.queue.timeout = timeout;
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.queue.in_flight.len()
+    }
+
+    /// Put a completed transfer back in free, its status as error
+    fn reclaim(&mut self, mut bulk: BulkTransfer) -> io::Result<()> {
+        let error = status_error(&bulk);
+        bulk.flush()?;
+        self.free.push(bulk);
+        match error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Submit bulk, if that fails it is kept as current
+    fn submit(&mut self, bulk: BulkTransfer) -> io::Result<()> {
+        self.queue.submit(bulk).map_err(|(error, bulk)| {
+            self.current = Some(bulk);
+            error
+        })
+    }
+
+    fn take_free(&mut self) -> io::Result<BulkTransfer> {
+        if let Some(bulk) = self.current.take() {
+            if bulk.len() < bulk.capacity() {
+                return Ok(bulk);
+            }
+            // Full, its submit failed in write()
+            self.submit(bulk)?;
+        }
+        if let Some(bulk) = self.free.pop() {
+            return Ok(bulk);
+        }
+        let bulk = self
+            .queue
+            .wait_oldest()?
+            .ok_or_else(|| Error::new(ErrorKind::WriteZero, "BulkSink has no transfers"))?;
+        self.reclaim(bulk)?;
+        Ok(self.free.pop().unwrap())
+    }
+}
+
+impl Write for BulkSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let mut bulk = self.take_free()?;
+        let n = bulk.write(buf)?;
+        if bulk.len() == bulk.capacity() {
+            // buf is taken, a failed submit is retried and reported by
+            // the next write() or flush()
+            let _ = self.submit(bulk);
+        } else {
+            self.current = Some(bulk);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(bulk) = self.current.take() {
+            self.submit(bulk)?;
+        }
+        let mut result = Ok(());
+        while let Some(bulk) = self.queue.wait_oldest()? {
+            if let Err(e) = self.reclaim(bulk) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+}
+
+impl Drop for BulkSink {
+    fn drop(&mut self) {
+        let timeout = self
+            .queue
+            .timeout
+            .map_or(DROP_TIMEOUT, |timeout| timeout.min(DROP_TIMEOUT));
+        self.queue.timeout = Some(timeout);
+        if self.flush().is_err() {
+            self.queue.cancel_all();
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use super::usb_device::{Speed, UsbDevice};
+use std::collections::HashMap;
+use std::fmt;
+
+/// A device and the devices connected to its downstream ports
+#[derive(Debug)]
+pub struct TopologyNode<'a> {
+    pub device: &'a UsbDevice,
+    /// Sorted by port number
+    pub children: Vec<TopologyNode<'a>>,
+}
+
+impl<'a> TopologyNode<'a> {
+    pub(crate) fn build<I>(devices: I) -> Vec<TopologyNode<'a>>
+    where
+        I: Iterator<Item = &'a UsbDevice>,
+    {
+        let devices: Vec<&UsbDevice> = devices.collect();
+        let known: Vec<&str> = devices.iter().map(|d| d.port_path.as_str()).collect();
+        let mut children: HashMap<&str, Vec<&UsbDevice>> = HashMap::new();
+        let mut roots = Vec::new();
+        for device in devices {
+            match device.parent.as_deref() {
+                Some(parent) if known.contains(&parent) => {
+                    children.entry(parent).or_default().push(device)
+                }
+                _ => roots.push(device),
+            }
+        }
+        roots.sort_by_key(|d| (d.bus_num, d.ports()));
+        roots
+            .into_iter()
+            .map(|device| Self::with_children(device, &mut children))
+            .collect()
+    }
+
+    fn with_children(
+        device: &'a UsbDevice,
+        children: &mut HashMap<&str, Vec<&'a UsbDevice>>,
+    ) -> Self {
+        let mut below = children
+            .remove(device.port_path.as_str())
+            .unwrap_or_default();
+        below.sort_by_key(|d| d.port_number);
+        TopologyNode {
+            device,
+            children: below
+                .into_iter()
+                .map(|child| Self::with_children(child, children))
+                .collect(),
+        }
+    }
+
+    /// Find node by sysfs port path in this subtree
+    pub fn find(&self, port_path: &str) -> Option<&TopologyNode<'a>> {
+        if self.device.port_path == port_path {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(port_path))
+    }
+
+    fn fmt_indent(&self, f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+        let device = self.device;
+        write!(
+            f,
+            "{:indent$}{} {:04x}:{:04x} {} [{}]",
+            "",
+            device.port_path,
+            device.device.id_vendor,
+            device.device.id_product,
+            device.product,
+            device.speed,
+            indent = depth * 2
+        )?;
+        if device.max_child > 0 {
+            write!(f, " {} ports", device.max_child)?;
+        }
+        writeln!(f)?;
+        for child in &self.children {
+            child.fmt_indent(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TopologyNode<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_indent(f, 0)
+    }
+}
+
+/// Where an open device is connected, from USBDEVFS_CONNINFO_EX
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub bus_num: u8,
+    pub dev_num: u8,
+    pub speed: Speed,
+    /// Ports from the root hub down to the device
+    pub ports: Vec<u8>,
+}
+
+impl ConnectionInfo {
+    /// sysfs style port path like 1-2.3
+    pub fn port_path(&self) -> String {
+        if self.ports.is_empty() {
+            return format!("usb{}", self.bus_num);
+        }
+        let ports: Vec<String> = self.ports.iter().map(|p| p.to_string()).collect();
+        format!("{}-{}", self.bus_num, ports.join("."))
+    }
+}
+
+impl fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}-{}) {}",
+            self.port_path(),
+            self.bus_num,
+            self.dev_num,
+            self.speed
+        )
+    }
+}
@@ -4,7 +4,13 @@ use crate::UsbCore;
 #[cfg(feature = "serde")]
 use serde::Serialize;
 use std::fmt;
+use std::fs;
+use std::io;
 use std::io::prelude::*;
+use std::io::{Error, ErrorKind, SeekFrom};
+use std::path::PathBuf;
+
+const SYSFS_DEVICES: &str = "/sys/bus/usb/devices";
 #[cfg_attr(feature = "serde", derive(Serialize))]
 #[derive(Debug)]
 pub struct UsbDevice {
@@ -13,9 +19,80 @@ pub struct UsbDevice {
     pub manufacturer: String,
     pub product: String,
     pub serial: String,
+    /// sysfs name like 1-2.3 or usb1 for root hubs, empty if not enumerated from sysfs
+    pub port_path: String,
+    pub speed: Speed,
+    /// Port chain below the root hub like 2.3, 0 for root hubs
+    pub devpath: String,
+    /// port_path of the hub this device is connected to, None for root hubs
+    pub parent: Option<String>,
+    /// Port on the parent hub, 0 for root hubs
+    pub port_number: u8,
+    /// Number of downstream ports, 0 if not a hub
+    pub max_child: u8,
+    /// Device directory in sysfs used for power and driver attributes
+    pub sysfs_path: Option<PathBuf>,
     pub device: Device,
 }
 
+/// Negotiated link speed, discriminants match the kernel usb_device_speed
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Speed {
+    #[default]
+    Unknown = 0,
+    Low = 1,
+    Full = 2,
+    High = 3,
+    Wireless = 4,
+    Super = 5,
+    SuperPlus = 6,
+}
+
+impl Speed {
+    /// Parse the sysfs speed attribute given in Mbit/s
+    pub fn from_sysfs(mbps: &str) -> Self {
+        match mbps.trim() {
+            "1.5" => Speed::Low,
+            "12" => Speed::Full,
+            "480" => Speed::High,
+            "53.3-480" => Speed::Wireless,
+            "5000" => Speed::Super,
+            "10000" | "20000" => Speed::SuperPlus,
+            _ => Speed::Unknown,
+        }
+    }
+}
+
+impl From<u32> for Speed {
+    fn from(speed: u32) -> Self {
+        match speed {
+            1 => Speed::Low,
+            2 => Speed::Full,
+            3 => Speed::High,
+            4 => Speed::Wireless,
+            5 => Speed::Super,
+            6 => Speed::SuperPlus,
+            _ => Speed::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let speed = match self {
+            Speed::Unknown => "unknown",
+            Speed::Low => "1.5 Mbit/s",
+            Speed::Full => "12 Mbit/s",
+            Speed::High => "480 Mbit/s",
+            Speed::Wireless => "wireless",
+            Speed::Super => "5 Gbit/s",
+            Speed::SuperPlus => "10 Gbit/s or more",
+        };
+        write!(f, "{}", speed)
+    }
+}
+
 impl fmt::Display for UsbDevice {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}-{}\n{}", self.bus_num, self.dev_num, self.device)
@@ -38,6 +115,13 @@ impl UsbDevice {
             product,
             manufacturer,
             serial,
+            port_path: String::new(),
+            speed: Speed::Unknown,
+            devpath: String::new(),
+            parent: None,
+            port_number: 0,
+            max_child: 0,
+            sysfs_path: None,
         }
     }
 
@@ -48,7 +132,7 @@ impl UsbDevice {
     where
         F: FnMut(&mut Self),
     {
-        use std::io::{Error, ErrorKind};
+        use std::io::Error;
         let mut descs = Descriptor::from_bytes(vec.bytes())?;
         // The first descriptor should be the device
         // If not well then something is bad
@@ -105,8 +189,61 @@ impl UsbDevice {
         Ok(device)
     }
 
+    /// Ports from the root hub down to this device, empty for root hubs
+    pub fn ports(&self) -> Vec<u8> {
+        port_numbers(&self.devpath)
+    }
+
+    pub fn is_root_hub(&self) -> bool {
+        self.port_path.starts_with("usb")
+    }
+
+    pub fn is_hub(&self) -> bool {
+        self.device.device_class == 0x09
+    }
+
+    /// Set devpath, parent and port_number from the sysfs name
+    pub(crate) fn set_port_path(&mut self, port_path: &str) {
+        self.port_path = port_path.into();
+        self.devpath = match port_path.split_once('-') {
+            Some((_, devpath)) => devpath.into(),
+            None => "0".into(),
+        };
+        self.port_number = self.ports().last().copied().unwrap_or(0);
+        self.parent = if self.is_root_hub() || port_path.is_empty() {
+            None
+        } else if let Some((parent, _)) = port_path.rsplit_once('.') {
+            Some(parent.into())
+        } else {
+            Some(format!("usb{}", self.bus_num))
+        };
+    }
+
+    /// Read a sysfs attribute relative to sysfs_path, like power/control
+    pub fn read_attribute(&self, name: &str) -> io::Result<String> {
+        let value = fs::read(self.attribute_path(name)?)?;
+        Ok(String::from_utf8_lossy(&value).trim().to_string())
+    }
+
+    /// Write a sysfs attribute relative to sysfs_path, most need root
+    pub fn write_attribute(&self, name: &str, value: &str) -> io::Result<()> {
+        fs::write(self.attribute_path(name)?, value)
+    }
+
+    fn attribute_path(&self, name: &str) -> io::Result<PathBuf> {
+        match &self.sysfs_path {
+            Some(path) => Ok(path.join(name)),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{}-{} has no sysfs path", self.bus_num, self.dev_num),
+            )),
+        }
+    }
+
     pub fn from_usbcore(usb: &mut UsbCore) -> Result<Self, std::io::Error> {
         let mut bytes = Vec::new();
+        // The fd may have been read before, like one inherited from another process
+        usb.handle().seek(SeekFrom::Start(0))?;
         usb.handle().read_to_end(&mut bytes)?;
         Self::from_bytes(bytes, |d| {
             d.bus_num = usb.bus_dev.0;
@@ -120,6 +257,23 @@ impl UsbDevice {
             d.serial = usb
                 .get_descriptor_string(d.device.iserial)
                 .unwrap_or_else(|_| String::new());
+            if let Ok(info) = usb.connection_info() {
+                d.speed = info.speed;
+                d.set_port_path(&info.port_path());
+                let path = PathBuf::from(SYSFS_DEVICES).join(&d.port_path);
+                if path.is_dir() {
+                    d.sysfs_path = Some(path);
+                }
+            }
         })
     }
 }
+
+/// Parse a devpath like 2.3.1 into port numbers
+pub(crate) fn port_numbers(devpath: &str) -> Vec<u8> {
+    devpath
+        .split('.')
+        .filter_map(|port| port.parse().ok())
+        .filter(|port| *port != 0)
+        .collect()
+}
@@ -1,20 +1,38 @@
+use super::capabilities::Capabilities;
+use super::constants::*;
+use super::disconnect::{check_connected, mark_disconnect, Disconnected};
+use super::topology::ConnectionInfo;
+use super::usb_device::Speed;
 use super::usbfsurb::*;
+use crate::descriptors::descriptor::string_from_descriptor;
 use crate::endpoint::Endpoint;
+use crate::request::*;
 use crate::usb_transfer::*;
 use crate::TimeoutMillis;
 use crate::UsbDevice;
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::*;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CStr;
 use std::io;
-use std::io::Write;
 use std::io::{Error, ErrorKind};
 use std::mem;
 use std::os::unix::io::AsRawFd;
 use std::os::unix::prelude::*;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 const CONTROL_MAX_PACKET_SIZE: u16 = 2048;
+const STANDARD_REQUEST_TIMEOUT_MS: u32 = 1000;
+/// Largest URB the kernel takes without Capabilities::NO_PACKET_SIZE_LIM
+pub(crate) const MAX_URB_SIZE: usize = 16384;
+// URBs of a split bulk transfer kept in flight, all count against usbfs_memory_mb
+const MAX_SPLIT_IN_FLIGHT: usize = 16;
+// Largest URB of a split transfer with Capabilities::BULK_SCATTER_GATHER or
+// NO_PACKET_SIZE_LIM, keeps a full window well below the 16 MiB usbfs_memory_mb default
+const MAX_SPLIT_URB_SIZE: usize = 256 * 1024;
 #[macro_export]
 macro_rules! ioctl_read_ptr {
     ($(#[$attr:meta])* $name:ident, $ioty:expr, $nr:expr, $ty:ty) => (
@@ -76,6 +94,30 @@ union UrbUnion {
     stream_id: u32,
 }
 
+#[repr(C)]
+#[derive(Default)]
+pub struct UsbFsConnInfoEx {
+    size: u32,
+    busnum: u32,
+    devnum: u32,
+    speed: u32,
+    num_ports: u8,
+    ports: [u8; 7],
+}
+
+// Sync control transfer, struct usbdevfs_ctrltransfer
+#[derive(Debug)]
+#[repr(C)]
+pub struct CControlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout_ms: u32,
+    data: *mut libc::c_void,
+}
+
 // Sync bulk transfer
 #[derive(Debug)]
 #[repr(C)]
@@ -86,20 +128,40 @@ pub struct CBulkTransfer {
     data: *mut libc::c_void,
 }
 
+// Completion callback of submit_bulk_with, Some resubmits the transfer
+type BulkCallback = Box<dyn FnMut(BulkTransfer) -> Option<BulkTransfer> + Send>;
+
 pub struct UsbFs {
     pub(crate) handle: std::fs::File,
     claims: Vec<u32>,
-    capabilities: u32,
+    capabilities: Capabilities,
     transfers: Vec<TransferKind>,
+    // submitted URBs by buffer address, needed to discard them
+    in_flight: HashMap<usize, *mut UsbFsUrb>,
     pub(crate) bus_dev: (u8, u8),
     descriptors: Option<UsbDevice>,
     read_only: bool,
-    use_mmap: bool
+    use_mmap: bool,
+    // Set by drop_privileges
+    allowed_interfaces: Option<u32>,
+    disconnected: Arc<AtomicBool>,
+    // Deadlines of URBs submitted with a timeout, by buffer address
+    deadlines: HashMap<usize, Instant>,
+    // Discarded because the deadline passed
+    timed_out: HashSet<usize>,
+    timer: Option<OwnedFd>,
+    // Callbacks of transfers submitted with submit_bulk_with, by buffer address
+    callbacks: HashMap<usize, BulkCallback>,
 }
 
-ioctl_readwrite_ptr!(usb_control_transfer, b'U', 0, ControlTransfer);
+// The URB pointers in in_flight are owned by the kernel until reaped,
+// nothing ties them to the thread that submitted them.
+unsafe impl Send for UsbFs {}
+
+ioctl_readwrite_ptr!(usb_control_transfer, b'U', 0, CControlTransfer);
 ioctl_readwrite_ptr!(usb_bulk_transfer, b'U', 2, CBulkTransfer);
 ioctl_read_ptr!(usb_set_interface, b'U', 4, UsbFsSetInterface);
+ioctl_read_ptr!(usb_set_configuration, b'U', 5, u32);
 ioctl_write_ptr!(usb_get_driver, b'U', 8, UsbFsGetDriver);
 ioctl_read_ptr!(usb_submit_urb, b'U', 10, UsbFsUrb);
 ioctl_write_ptr!(usb_reapurbndelay, b'U', 13, *mut UsbFsUrb);
@@ -108,7 +170,103 @@ ioctl_read_ptr!(usb_release_interface, b'U', 16, u32);
 ioctl_readwrite_ptr!(usb_ioctl, b'U', 18, UsbFsIoctl);
 ioctl_read!(usb_get_capabilities, b'U', 26, u32);
 ioctl_none!(usb_reset, b'U', 20);
+ioctl_write_ptr!(usb_drop_privileges, b'U', 30, u32);
 ioctl_read!(usb_clear_halt, b'U', 21, u32);
+ioctl_read!(usb_conninfo_ex, b'U', 32, UsbFsConnInfoEx);
+ioctl_none!(usb_forbid_suspend, b'U', 33);
+ioctl_none!(usb_allow_suspend, b'U', 34);
+ioctl_none!(usb_wait_for_resume, b'U', 35);
+
+/// USBDEVFS_DISCARDURB is _IO but takes the URB pointer as argument
+/// # Safety
+/// ioctl call need unsafe calls to C
+unsafe fn usb_discard_urb(fd: libc::c_int, urb: *mut UsbFsUrb) -> nix::Result<libc::c_int> {
+    convert_ioctl_res!(libc::ioctl(
+        fd,
+        request_code_none!(b'U', 11) as nix::sys::ioctl::ioctl_num_type,
+        urb
+    ))
+}
+
+/// /dev/bus/usb nodes are char 189 with minor (bus - 1) * 128 + dev - 1
+fn bus_dev_from_rdev(rdev: libc::dev_t) -> Option<(u8, u8)> {
+    const USB_DEVICE_MAJOR: u64 = 189;
+    if nix::sys::stat::major(rdev) != USB_DEVICE_MAJOR {
+        return None;
+    }
+    let minor = nix::sys::stat::minor(rdev);
+    Some(((minor / 128 + 1) as u8, (minor % 128 + 1) as u8))
+}
+
+/// Longest data of USBDEVFS_CONTROL, the kernel copies it through one
+/// page and fails with EINVAL when wLength is larger than PAGE_SIZE
+fn control_max_length() -> usize {
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if page_size <= 0 {
+        return 4096;
+    }
+    (page_size as usize).min(u16::MAX as usize)
+}
+
+/// USBDEVFS_BULK on fd, blocks until done or timeout.
+/// Fails with Disconnected once disconnected is set and sets it on ENODEV/ESHUTDOWN.
+pub(crate) fn bulk_transfer(
+    fd: RawFd,
+    disconnected: &AtomicBool,
+    ep: u8,
+    mem: *mut libc::c_void,
+    length: u32,
+    timeout: TimeoutMillis,
+) -> io::Result<u32> {
+    check_connected(disconnected)?;
+    let mut bulk = CBulkTransfer {
+        ep: ep as u32,
+        length,
+        timeout_ms: timeout.0,
+        data: mem,
+    };
+
+    let res = unsafe { usb_bulk_transfer(fd, &mut bulk) }
+        .map_err(|_| mark_disconnect(disconnected, io::Error::last_os_error()))?;
+    Ok(res as u32)
+}
+
+/// USBDEVFS_CONTROL on fd, blocks until done or timeout.
+/// The direction bit of request_type decides if data is read or written.
+/// Disconnected is handled as in bulk_transfer().
+pub(crate) fn control_transfer(
+    fd: RawFd,
+    disconnected: &AtomicBool,
+    req: ControlRequest,
+    data: *mut libc::c_void,
+    length: usize,
+    timeout: TimeoutMillis,
+) -> io::Result<u32> {
+    let max_length = control_max_length();
+    if length > max_length {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Data bigger than {} is not supported on the control endpoint",
+                max_length
+            ),
+        ));
+    }
+    check_connected(disconnected)?;
+    let mut ctrl = CControlTransfer {
+        request_type: req.request_type.into(),
+        request: req.request,
+        value: req.value,
+        index: req.index,
+        length: length as u16,
+        timeout_ms: timeout.0,
+        data,
+    };
+
+    let res = unsafe { usb_control_transfer(fd, &mut ctrl) }
+        .map_err(|_| mark_disconnect(disconnected, io::Error::last_os_error()))?;
+    Ok(res as u32)
+}
 
 impl UsbCoreDriver for UsbFs {
     // Create a new BulkTransfer for reading
@@ -118,8 +276,7 @@ impl UsbCoreDriver for UsbFs {
     // usb.submit_bulk(transfer);
     // ```
     fn new_bulk_in(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
-        let (ptr, deallocate) = self.mmap(buffer_capacity)?;
-        Ok(BulkTransfer::input(ep, ptr, buffer_capacity, deallocate))
+        Ok(BulkTransfer::input(ep, self.mmap(buffer_capacity)?))
     }
 
     // Create a new BulkTransfer for reading
@@ -129,8 +286,7 @@ impl UsbCoreDriver for UsbFs {
     // usb.submit_bulk(transfer);
     // ```
     fn new_bulk_out(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
-        let (ptr, deallocate) = self.mmap(buffer_capacity)?;
-        Ok(BulkTransfer::output(ep, ptr, buffer_capacity, deallocate))
+        Ok(BulkTransfer::output(ep, self.mmap(buffer_capacity)?))
     }
 
     fn new_control(
@@ -151,25 +307,52 @@ impl UsbCoreDriver for UsbFs {
             ));
         }
 
-        let capacity_length = length as usize + 8;
-        let (p, deallocate) = self.mmap(capacity_length)?;
-        let mut ctrl = ControlTransfer::new(p, capacity_length as u16, 0, deallocate);
-        ctrl.write_all(&[
-            request_type,
-            request,
-            (value & 0x00FF) as u8,
-            (value >> 8) as u8,
-            (index & 0x00FF) as u8,
-            (index >> 8) as u8,
-            (length & 0x00FF) as u8,
-            (length >> 8) as u8,
-        ])?;
+        let buffer = self.mmap(length as usize + 8)?;
+        ControlTransfer::with_setup(buffer, request_type, request, value, index, length)
+    }
+}
+
+impl UsbCoreBlocking for UsbFs {
+    fn bulk_read(&mut self, ep: u8, mem: &mut [u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        UsbFs::bulk_read(self, ep, mem, timeout)
+    }
 
-        Ok(ctrl)
+    fn bulk_write(&mut self, ep: u8, mem: &[u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        UsbFs::bulk_write(self, ep, mem, timeout)
+    }
+
+    fn control_async_wait(
+        &mut self,
+        ctrl: ControlTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<ControlTransfer> {
+        UsbFs::control_async_wait(self, ctrl, timeout)
     }
 }
 
 impl UsbFs {
+    fn new(handle: std::fs::File, bus_dev: (u8, u8), read_only: bool) -> UsbFs {
+        let mut usb = UsbFs {
+            handle,
+            claims: vec![],
+            capabilities: Capabilities::empty(),
+            transfers: Vec::new(),
+            in_flight: HashMap::new(),
+            descriptors: None,
+            bus_dev,
+            read_only,
+            use_mmap: true,
+            allowed_interfaces: None,
+            disconnected: Arc::new(AtomicBool::new(false)),
+            deadlines: HashMap::new(),
+            timed_out: HashSet::new(),
+            timer: None,
+            callbacks: HashMap::new(),
+        };
+        usb.capabilities = usb.read_capabilities();
+        usb
+    }
+
     pub fn from_device(device: &UsbDevice) -> io::Result<UsbFs> {
         UsbFs::from_bus_device(device.bus_num, device.dev_num)
     }
@@ -186,17 +369,8 @@ impl UsbFs {
         )
         .map_err(|_| io::Error::last_os_error())?;
 
-        let mut res = UsbFs {
-            handle: unsafe { std::fs::File::from_raw_fd(handle) },
-            claims: vec![],
-            capabilities: 0,
-            transfers: Vec::new(),
-            descriptors: None,
-            bus_dev: (bus, dev),
-            read_only: true,
-	    use_mmap: true
-        };
-
+        let handle = unsafe { std::fs::File::from_raw_fd(handle) };
+        let mut res = UsbFs::new(handle, (bus, dev), true);
         res.descriptors();
 
         Ok(res)
@@ -213,30 +387,126 @@ impl UsbFs {
         )
         .map_err(|_| io::Error::last_os_error())?;
 
-        let res = UsbFs {
-            handle: unsafe { std::fs::File::from_raw_fd(handle) },
-            claims: vec![],
-            capabilities: 0,
-            transfers: Vec::new(),
-            descriptors: None,
-            bus_dev: (bus, dev),
-            read_only: false,
-	    use_mmap: true
-        };
+        let handle = unsafe { std::fs::File::from_raw_fd(handle) };
+        Ok(UsbFs::new(handle, (bus, dev), false))
+    }
 
-        // res.descriptors();
+    /// Use a usbfs file descriptor opened by a more privileged process,
+    /// like one passed over a unix socket before entering a sandbox.
+    pub fn from_fd(fd: OwnedFd) -> io::Result<UsbFs> {
+        UsbFs::from_file(std::fs::File::from(fd))
+    }
 
-        Ok(res)
+    /// Use an already open device node, like a file descriptor from Android
+    /// UsbManager, xdg-desktop-portal or systemd. Bus and device number are
+    /// taken from the device number of the node or USBDEVFS_CONNINFO_EX.
+    pub fn from_file(file: std::fs::File) -> io::Result<UsbFs> {
+        let stat =
+            nix::sys::stat::fstat(file.as_raw_fd()).map_err(|_| io::Error::last_os_error())?;
+        if stat.st_mode & libc::S_IFMT != libc::S_IFCHR {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "File is not a usbfs character device",
+            ));
+        }
+        Ok(UsbFs::from_open_file(file))
+    }
+
+    fn from_open_file(file: std::fs::File) -> UsbFs {
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        let fd = file.as_raw_fd();
+        let read_only = fcntl(fd, FcntlArg::F_GETFL)
+            .map(|flags| OFlag::from_bits_truncate(flags) & OFlag::O_ACCMODE == OFlag::O_RDONLY)
+            .unwrap_or(false);
+        let bus_dev = nix::sys::stat::fstat(fd)
+            .ok()
+            .and_then(|stat| bus_dev_from_rdev(stat.st_rdev))
+            .unwrap_or((0, 0));
+        let mut usb = UsbFs::new(file, bus_dev, read_only);
+        if usb.bus_dev == (0, 0) {
+            if let Ok(info) = usb.connection_info() {
+                usb.bus_dev = (info.bus_num, info.dev_num);
+            }
+        }
+        usb
+    }
+
+    /// Give up the right to claim interfaces not in the interfaces bit mask,
+    /// to detach kernel drivers and to reset or reconfigure the device
+    /// unless all interfaces are claimed. Can not be undone.
+    /// Needs Linux 4.6 or later.
+    pub fn drop_privileges(&mut self, interfaces: u32) -> io::Result<()> {
+        if !self.capabilities.drop_privileges() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                "Kernel does not support USBDEVFS_DROP_PRIVILEGES",
+            ));
+        }
+        unsafe { usb_drop_privileges(self.handle.as_raw_fd(), &interfaces) }
+            .map_err(|_| self.last_error())?;
+        // The kernel keeps the intersection of all masks
+        self.allowed_interfaces = Some(self.allowed_interfaces.unwrap_or(u32::MAX) & interfaces);
+        Ok(())
+    }
+
+    /// Interfaces that can be claimed, None if privileges are not dropped
+    pub fn allowed_interfaces(&self) -> Option<u32> {
+        self.allowed_interfaces
+    }
+
+    fn check_allowed(&self, interface: u32) -> io::Result<()> {
+        match self.allowed_interfaces {
+            Some(mask) if interface >= 32 || mask & (1 << interface) == 0 => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!(
+                    "Interface {} is not in the drop_privileges mask 0x{:08X}",
+                    interface, mask
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Last OS error, Disconnected if it was ENODEV or ESHUTDOWN
+    fn last_error(&self) -> io::Error {
+        mark_disconnect(&self.disconnected, io::Error::last_os_error())
+    }
+
+    fn connected(&self) -> io::Result<()> {
+        check_connected(&self.disconnected)
+    }
+
+    /// Flag shared with UsbHandle, set once the device is gone
+    pub(crate) fn disconnected_flag(&self) -> Arc<AtomicBool> {
+        self.disconnected.clone()
+    }
+
+    /// True once a call failed with Disconnected or the fd reports
+    /// POLLERR/POLLHUP. Outstanding URBs can still be reaped if the
+    /// kernel has Capabilities::REAP_AFTER_DISCONNECT.
+    pub fn is_disconnected(&self) -> bool {
+        if self.disconnected.load(Ordering::Relaxed) {
+            return true;
+        }
+        let mut fds = [PollFd::new(self.handle.as_raw_fd(), PollFlags::POLLOUT)];
+        let hangup = poll(&mut fds, 0).is_ok()
+            && fds[0]
+                .revents()
+                .is_some_and(|revents| revents.intersects(PollFlags::POLLERR | PollFlags::POLLHUP));
+        if hangup {
+            self.disconnected.store(true, Ordering::Relaxed);
+        }
+        hangup
     }
 
     pub fn set_use_mmap(&mut self, use_mmap: bool) {
-	self.use_mmap = use_mmap;
+        self.use_mmap = use_mmap;
     }
 
     pub fn reset(&mut self) -> io::Result<()> {
         let res = unsafe { usb_reset(self.handle.as_raw_fd()) };
         match res {
-            Err(_) => Err(io::Error::last_os_error()),
+            Err(_) => Err(self.last_error()),
             Ok(_) => Ok(()),
         }
     }
@@ -247,11 +517,51 @@ impl UsbFs {
             usb_clear_halt(self.handle.as_raw_fd(), &mut ep32)
         };
         match res {
-            Err(_) => Err(io::Error::last_os_error()),
+            Err(_) => Err(self.last_error()),
             Ok(_) => Ok(()),
         }
     }
 
+    /// Bus, address, speed and port chain of the open device.
+    /// Needs Linux 5.6 or later.
+    pub fn connection_info(&self) -> io::Result<ConnectionInfo> {
+        let mut info = UsbFsConnInfoEx::default();
+        unsafe { usb_conninfo_ex(self.handle.as_raw_fd(), &mut info) }
+            .map_err(|_| self.last_error())?;
+        let num_ports = (info.num_ports as usize).min(info.ports.len());
+        Ok(ConnectionInfo {
+            bus_num: info.busnum as u8,
+            dev_num: info.devnum as u8,
+            speed: Speed::from(info.speed),
+            ports: info.ports[..num_ports].to_vec(),
+        })
+    }
+
+    pub fn speed(&self) -> io::Result<Speed> {
+        Ok(self.connection_info()?.speed)
+    }
+
+    /// Keep the device resumed while open, default after open.
+    /// Needs Linux 5.7 or later.
+    pub fn forbid_suspend(&self) -> io::Result<()> {
+        unsafe { usb_forbid_suspend(self.handle.as_raw_fd()) }.map_err(|_| self.last_error())?;
+        Ok(())
+    }
+
+    /// Let the device runtime suspend when idle.
+    /// URBs fail while suspended, use wait_for_resume() before submit.
+    pub fn allow_suspend(&self) -> io::Result<()> {
+        unsafe { usb_allow_suspend(self.handle.as_raw_fd()) }.map_err(|_| self.last_error())?;
+        Ok(())
+    }
+
+    /// Block until the device is resumed, by remote wakeup or by another user.
+    /// Suspend is forbidden again when this returns.
+    pub fn wait_for_resume(&self) -> io::Result<()> {
+        unsafe { usb_wait_for_resume(self.handle.as_raw_fd()) }.map_err(|_| self.last_error())?;
+        Ok(())
+    }
+
     pub fn handle(&self) -> &std::fs::File {
         &self.handle
     }
@@ -264,22 +574,22 @@ impl UsbFs {
         &self.descriptors
     }
 
-    pub fn capabilities(&mut self) -> io::Result<u32> {
-        if self.capabilities != 0 {
-            return Ok(self.capabilities);
-        }
+    /// Read when opened, empty on kernels older than 3.6
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
 
-        let res = unsafe { usb_get_capabilities(self.handle.as_raw_fd(), &mut self.capabilities) };
-        if res != Ok(0) {
-            return Err(io::Error::last_os_error());
+    fn read_capabilities(&self) -> Capabilities {
+        let mut capabilities = 0;
+        match unsafe { usb_get_capabilities(self.handle.as_raw_fd(), &mut capabilities) } {
+            Ok(_) => Capabilities::from_bits_truncate(capabilities),
+            Err(_) => Capabilities::empty(),
         }
-
-        Ok(self.capabilities)
     }
 
     /// Returns latest transmitted async result or an error.
     /// Example:
-    /// ```
+    /// ```ignore
     /// let mut urb = usb.new_bulk(1, 64);
     /// // poll then
     /// let urb = usb.async_response()
@@ -290,25 +600,97 @@ impl UsbFs {
     /// }
     /// ```
     /// The returned Transfer can be reused after call transfer.flush()
+    /// Transfers submitted with submit_bulk_with() are handed to their
+    /// callback here and not returned.
     pub fn async_response(&mut self) -> io::Result<TransferKind> {
-        let urb: *mut UsbFsUrb = ptr::null_mut();
-        let urb = unsafe {
-            let _ = usb_reapurbndelay(self.handle.as_raw_fd(), &urb)
-                .map_err(|_| io::Error::last_os_error())?;
-            if urb.is_null() {
-                panic!("URB must not be null something is buggy send bug report to usbapi-rs developer");
+        self.expire_transfers()?;
+        loop {
+            let urb = match self.reap_urb() {
+                Ok(urb) => unsafe { Box::from_raw(urb) },
+                Err(e) => {
+                    if Disconnected::is(&e) && !self.capabilities.reap_after_disconnect() {
+                        // Without REAP_AFTER_DISCONNECT the kernel does not give back
+                        // outstanding URBs, leak them rather than free memory it may use
+                        self.in_flight.clear();
+                        self.callbacks.clear();
+                    }
+                    return Err(e);
+                }
+            };
+            let transfer = self.complete_urb(*urb)?;
+            if let Some(transfer) = self.dispatch(transfer)? {
+                return Ok(transfer);
             }
-            Box::from_raw(urb)
+        }
+    }
+
+    /// Run the callback of a transfer from submit_bulk_with(), None if it had
+    /// one unless its resubmit failed
+    fn dispatch(&mut self, transfer: TransferKind) -> io::Result<Option<TransferKind>> {
+        let bulk = match transfer {
+            TransferKind::Bulk(bulk) if self.callbacks.contains_key(&bulk.buffer_address()) => bulk,
+            transfer => return Ok(Some(transfer)),
+        };
+        let mut callback = self.callbacks.remove(&bulk.buffer_address()).unwrap();
+        let mut bulk = match callback(bulk) {
+            Some(bulk) => bulk,
+            None => return Ok(None),
         };
+        bulk.actual_length = 0;
+        bulk.status = 0;
+        let buffer = bulk.buffer_address();
+        self.callbacks.insert(buffer, callback);
+        match self.try_submit_bulk(bulk) {
+            Ok(_) => Ok(None),
+            Err((e, mut bulk)) => {
+                self.callbacks.remove(&buffer);
+                let errno = if Disconnected::is(&e) {
+                    libc::ENODEV
+                } else {
+                    e.raw_os_error().unwrap_or(libc::EIO)
+                };
+                log::debug!(
+                    "Resubmit of bulk transfer on {} failed: {}",
+                    bulk.endpoint,
+                    e
+                );
+                bulk.status = -errno;
+                Ok(Some(TransferKind::Bulk(bulk)))
+            }
+        }
+    }
 
-        let ep = Endpoint::new(urb.endpoint);
-        if ep.is_bulk() {
-            Ok(TransferKind::Bulk(bulk_from_urb(*urb)?))
-        } else if ep.is_control() {
-            Ok(TransferKind::Control(control_from_urb(*urb)?))
-        } else {
-            Ok(TransferKind::Invalid(ep))
+    /// Forget a reaped URB and give back its transfer
+    fn complete_urb(&mut self, mut urb: UsbFsUrb) -> io::Result<TransferKind> {
+        let buffer = urb.buffer as usize;
+        self.in_flight.remove(&buffer);
+        // A URB that completed before the discard keeps its status
+        if self.timed_out.remove(&buffer)
+            && (urb.status == -libc::ENOENT || urb.status == -libc::ECONNRESET)
+        {
+            urb.status = -libc::ETIMEDOUT;
+        }
+        if self.deadlines.remove(&buffer).is_some() {
+            // It may have held the earliest deadline, the transfer is
+            // given back even if the timer could not be changed
+            if let Err(e) = self.arm_timer() {
+                log::error!("Failed to re-arm the transfer timer: {}", e);
+            }
+        }
+        transfer_from_urb(urb)
+    }
+
+    /// USBDEVFS_REAPURBNDELAY, WouldBlock if nothing has completed
+    fn reap_urb(&mut self) -> io::Result<*mut UsbFsUrb> {
+        let urb: *mut UsbFsUrb = ptr::null_mut();
+        unsafe { usb_reapurbndelay(self.handle.as_raw_fd(), &urb) }
+            .map_err(|_| self.last_error())?;
+        if urb.is_null() {
+            panic!(
+                "URB must not be null something is buggy send bug report to usbapi-rs developer"
+            );
         }
+        Ok(urb)
     }
 
     /// Read all URB responses if there are any pending and store it in transfers
@@ -317,7 +699,7 @@ impl UsbFs {
     /// collect_responses()
     ///
     /// Example usage:
-    /// ```
+    /// ```ignore
     /// usb.submit_bulk(bulk);
     /// poll.poll(&mut events, Duration::from_secs(1))?;
     /// if !events.is_empty() {
@@ -347,6 +729,12 @@ impl UsbFs {
         self.transfers.drain(..).collect()
     }
 
+    /// Store a transfer as if reap_own() had reaped it for another caller
+    #[cfg(test)]
+    pub(crate) fn push_response(&mut self, transfer: TransferKind) {
+        self.transfers.push(transfer);
+    }
+
     /// Claim interface
     ///
     /// * the `interface` number to claim
@@ -354,23 +742,38 @@ impl UsbFs {
     /// Examples
     ///
     /// Basic usage:
-    /// ```
+    /// ```ignore
     /// usb.claim_interface(1)
     /// ```
     ///
     pub fn claim_interface(&mut self, interface: u32) -> io::Result<()> {
-        let driver: UsbFsGetDriver = unsafe { mem::zeroed() };
+        self.connected()?;
+        self.check_allowed(interface)?;
+        let mut driver: UsbFsGetDriver = unsafe { mem::zeroed() };
+        driver.interface = interface as i32;
         let res = unsafe { usb_get_driver(self.handle.as_raw_fd(), &driver) };
         if res == Ok(0) {
-            let c_str: &CStr = CStr::from_bytes_with_nul(&driver.driver)
+            let c_str: &CStr = CStr::from_bytes_until_nul(&driver.driver)
                 .map_err(|e| Error::new(ErrorKind::Other, format!("{}", e)))?;
             let name: &str = c_str.to_str().unwrap_or("");
+            if name != "usbfs" && self.allowed_interfaces.is_some() {
+                return Err(Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!(
+                        "Interface {} is bound to {}, kernel drivers can not be detached after drop_privileges",
+                        interface, name
+                    ),
+                ));
+            }
             if name != "usbfs" {
-                panic!("FIXME the unload driver API is broken and need to be fixed");
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("Interface {} is bound to kernel driver {}", interface, name),
+                ));
             }
         }
         unsafe { usb_claim_interface(self.handle.as_raw_fd(), &interface) }
-            .map_err(|_| io::Error::last_os_error())?;
+            .map_err(|_| self.last_error())?;
         self.claims.push(interface);
         Ok(())
     }
@@ -381,7 +784,7 @@ impl UsbFs {
             alt_setting,
         };
         unsafe { usb_set_interface(self.handle.as_raw_fd(), &setter) }
-            .map_err(|_| io::Error::last_os_error())?;
+            .map_err(|_| self.last_error())?;
         Ok(())
     }
 
@@ -392,20 +795,25 @@ impl UsbFs {
     /// Examples
     ///
     /// Basic usage:
-    /// ```
+    /// ```ignore
     /// usb.release_interface(1)
     /// ```
     ///
     pub fn release_interface(&self, interface: u32) -> io::Result<()> {
         unsafe { usb_release_interface(self.handle.as_raw_fd(), &interface) }
-            .map_err(|_| io::Error::last_os_error())?;
+            .map_err(|_| self.last_error())?;
         Ok(())
     }
 
     ///
     /// Blocked bulk read
     /// Consider use @async_transfer() instead.
-    pub fn bulk_read(&self, ep: u8, mem: &mut [u8], timeout: TimeoutMillis) -> io::Result<u32> {
+    /// Reads larger than MAX_URB_SIZE are split, see bulk_split().
+    pub fn bulk_read(&mut self, ep: u8, mem: &mut [u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        self.connected()?;
+        if mem.len() > MAX_URB_SIZE {
+            return self.bulk_split(Endpoint::bulk_in(ep), mem.as_mut_ptr(), mem.len(), timeout);
+        }
         self.bulk(
             Endpoint::bulk_in(ep).into(),
             mem.as_mut_ptr() as *mut libc::c_void,
@@ -416,7 +824,14 @@ impl UsbFs {
 
     /// Blocked bulk write
     /// consider use @async_transfer() instead
-    pub fn bulk_write(&self, ep: u8, mem: &[u8], timeout: TimeoutMillis) -> io::Result<u32> {
+    /// Writes larger than MAX_URB_SIZE are split, see bulk_split().
+    pub fn bulk_write(&mut self, ep: u8, mem: &[u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        self.connected()?;
+        if mem.len() > MAX_URB_SIZE {
+            // The kernel only reads from mem on bulk out
+            let mem_ptr = mem.as_ptr() as *mut u8;
+            return self.bulk_split(Endpoint::bulk_out(ep), mem_ptr, mem.len(), timeout);
+        }
         self.bulk(
             ep & 0x7F,
             mem.as_ptr() as *mut libc::c_void,
@@ -425,6 +840,198 @@ impl UsbFs {
         )
     }
 
+    /// Blocked control transfer reading into data, the direction bit of
+    /// req.request_type is set. Returns the number of bytes received.
+    /// data can be at most one page (PAGE_SIZE) long, the kernel limit.
+    /// Example:
+    /// ```ignore
+    /// let req = ControlRequest::new(RequestType::new().vendor(), 0x01, 0, 0);
+    /// let mut version = [0u8; 4];
+    /// usb.control_in(req, &mut version, TimeoutMillis::from(100))?;
+    /// ```
+    pub fn control_in(
+        &mut self,
+        mut req: ControlRequest,
+        data: &mut [u8],
+        timeout: TimeoutMillis,
+    ) -> io::Result<u32> {
+        self.connected()?;
+        req.request_type = req.request_type.input();
+        self.control(
+            req,
+            data.as_mut_ptr() as *mut libc::c_void,
+            data.len(),
+            timeout,
+        )
+    }
+
+    /// Blocked control transfer sending data, the direction bit of
+    /// req.request_type is cleared. Returns the number of bytes sent.
+    pub fn control_out(
+        &mut self,
+        mut req: ControlRequest,
+        data: &[u8],
+        timeout: TimeoutMillis,
+    ) -> io::Result<u32> {
+        self.connected()?;
+        req.request_type = req.request_type.output();
+        // The kernel only reads from data on control out
+        self.control(req, data.as_ptr() as *mut libc::c_void, data.len(), timeout)
+    }
+
+    /// Bulk transfer of more than MAX_URB_SIZE as async URBs directly on mem.
+    /// URBs of MAX_SPLIT_URB_SIZE if the kernel can scatter-gather or has no
+    /// packet size limit, else of MAX_URB_SIZE. A short packet ends a read and
+    /// USBFS_URB_FLAGS_BULK_CONTINUATION makes the kernel cancel the rest
+    /// of the chain, without it reads are done one URB at a time.
+    /// On error every URB is discarded and reaped before returning, except
+    /// on disconnect where the kernel has already killed them.
+    fn bulk_split(
+        &mut self,
+        ep: Endpoint,
+        mem: *mut u8,
+        length: usize,
+        timeout: TimeoutMillis,
+    ) -> io::Result<u32> {
+        let capabilities = self.capabilities;
+        let chunk = if capabilities.bulk_scatter_gather() || capabilities.no_packet_size_limit() {
+            MAX_SPLIT_URB_SIZE
+        } else {
+            MAX_URB_SIZE
+        };
+        let continuation = capabilities.bulk_continuation();
+        let is_in = ep.is_bulk_in();
+        let window = if is_in && !continuation {
+            1
+        } else {
+            MAX_SPLIT_IN_FLIGHT
+        };
+        let chunks: Vec<(usize, usize)> = (0..length)
+            .step_by(chunk)
+            .map(|offset| (offset, chunk.min(length - offset)))
+            .collect();
+        let mut deadline = match timeout.0 {
+            0 => None,
+            ms => Some(Instant::now() + Duration::from_millis(ms as u64)),
+        };
+        let fd = self.handle.as_raw_fd();
+        let mut actual = vec![0; chunks.len()];
+        // Chunk index and URB, in submit order
+        let mut in_flight: Vec<(usize, *mut UsbFsUrb)> = Vec::new();
+        let mut next = 0;
+        let mut result = Ok(());
+        let mut stop = false;
+        let discard = |in_flight: &[(usize, *mut UsbFsUrb)]| {
+            for (_, urb) in in_flight {
+                let _ = unsafe { usb_discard_urb(fd, *urb) };
+            }
+        };
+
+        loop {
+            while !stop && next < chunks.len() && in_flight.len() < window {
+                let (offset, len) = chunks[next];
+                let mut flags = 0;
+                if is_in && continuation {
+                    if next > 0 {
+                        flags |= USBFS_URB_FLAGS_BULK_CONTINUATION;
+                    }
+                    if next + 1 < chunks.len() {
+                        flags |= USBFS_URB_FLAGS_SHORT_NOT_OK;
+                    }
+                }
+                let urb = UsbFsUrb::new(
+                    USBFS_URB_TYPE_BULK,
+                    ep,
+                    unsafe { mem.add(offset) },
+                    len,
+                    ptr::null_mut(),
+                )
+                .with_flags(flags);
+                let urb = Box::into_raw(Box::new(urb));
+                if unsafe { usb_submit_urb(fd, urb) }.is_err() {
+                    result = Err(self.last_error());
+                    drop(unsafe { Box::from_raw(urb) });
+                    stop = true;
+                    discard(&in_flight);
+                    break;
+                }
+                in_flight.push((next, urb));
+                next += 1;
+            }
+            if in_flight.is_empty() {
+                break;
+            }
+
+            let urb = match self.reap_own(&in_flight, deadline) {
+                Ok(urb) => urb,
+                // URBs are killed on disconnect, the kernel no longer uses mem
+                Err(e) if Disconnected::is(&e) => return Err(e),
+                Err(e) => {
+                    if !stop {
+                        result = Err(e);
+                        stop = true;
+                        discard(&in_flight);
+                    }
+                    // The kernel must give back every URB before mem is released
+                    deadline = None;
+                    continue;
+                }
+            };
+            let index = in_flight.iter().position(|(_, u)| *u == urb).unwrap();
+            let (n, _) = in_flight.remove(index);
+            let urb = unsafe { Box::from_raw(urb) };
+            if stop {
+                continue;
+            }
+            actual[n] = urb.actual_length as usize;
+            let short = is_in && actual[n] < chunks[n].1;
+            if urb.status != 0 && !(short && urb.status == -libc::EREMOTEIO) {
+                result = Err(io::Error::from_raw_os_error(-urb.status));
+                stop = true;
+                discard(&in_flight);
+            } else if short {
+                stop = true;
+                discard(&in_flight);
+            }
+        }
+        result?;
+
+        let mut total = 0;
+        for ((_, len), actual) in chunks.iter().zip(actual) {
+            total += actual;
+            if actual < *len {
+                break;
+            }
+        }
+        Ok(total as u32)
+    }
+
+    /// Reap until one of urbs completes, others are stored in transfers
+    fn reap_own(
+        &mut self,
+        urbs: &[(usize, *mut UsbFsUrb)],
+        deadline: Option<Instant>,
+    ) -> io::Result<*mut UsbFsUrb> {
+        loop {
+            match self.reap_urb() {
+                Ok(urb) if urbs.iter().any(|(_, u)| *u == urb) => return Ok(urb),
+                Ok(urb) => {
+                    let transfer = self.complete_urb(*unsafe { Box::from_raw(urb) })?;
+                    if let Some(transfer) = self.dispatch(transfer)? {
+                        self.transfers.push(transfer);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    if deadline.is_some_and(|deadline| deadline <= Instant::now()) {
+                        return Err(Error::new(ErrorKind::TimedOut, ""));
+                    }
+                    self.wait_for_urb(deadline)?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
     fn bulk(
         &self,
         ep: u8,
@@ -432,16 +1039,31 @@ impl UsbFs {
         length: u32,
         timeout: TimeoutMillis,
     ) -> io::Result<u32> {
-        let mut bulk = CBulkTransfer {
-            ep: ep as u32,
+        bulk_transfer(
+            self.handle.as_raw_fd(),
+            &self.disconnected,
+            ep,
+            mem,
             length,
-            timeout_ms: timeout.0,
-            data: mem,
-        };
+            timeout,
+        )
+    }
 
-        let res = unsafe { usb_bulk_transfer(self.handle.as_raw_fd(), &mut bulk) }
-            .map_err(|_| io::Error::last_os_error())?;
-        Ok(res as u32)
+    fn control(
+        &self,
+        req: ControlRequest,
+        data: *mut libc::c_void,
+        length: usize,
+        timeout: TimeoutMillis,
+    ) -> io::Result<u32> {
+        control_transfer(
+            self.handle.as_raw_fd(),
+            &self.disconnected,
+            req,
+            data,
+            length,
+            timeout,
+        )
     }
 
     /// Get descriptor string with id for default interface
@@ -464,33 +1086,14 @@ impl UsbFs {
             ));
         }
         let ctrl = self.new_control_in(
-            0x80,               // request_type
-            0x06,               // request
-            0x0300 | id as u16, // value
-            iface,              // index
-            256,                // Max read
+            RequestType::new().into(),
+            REQUEST_GET_DESCRIPTOR,
+            (DESCRIPTOR_STRING as u16) << 8 | id as u16,
+            iface,
+            256, // Max read
         )?;
         match self.control_async_wait(ctrl, TimeoutMillis::from(100)) {
-            Ok(control) => {
-                let data = control.buffer_from_raw();
-                let length = data.len();
-                if length % 2 != 0 || length <= 2 {
-                    log::error!(
-                        "Received an odd or short descriptor string of length {} for ID {}",
-                        length,
-                        id
-                    );
-                    return Ok("Invalid descriptor".into());
-                }
-                let n = length / 2;
-                let mut x = [0; 2];
-                let mut utf16 = Vec::with_capacity(n);
-                for i in 1..n {
-                    x.copy_from_slice(&data[2 * i..2 * i + 2]);
-                    utf16.push(u16::from_le_bytes(x));
-                }
-                Ok(String::from_utf16_lossy(&utf16))
-            }
+            Ok(control) => Ok(string_from_descriptor(control.as_slice(), id)),
             Err(e) => Err(Error::new(
                 ErrorKind::Other,
                 format!("Failed to get descriptor string cause: {}", e),
@@ -498,40 +1101,25 @@ impl UsbFs {
         }
     }
 
-    fn mmap(&mut self, length: usize) -> io::Result<(*mut u8, Deallocate)> {
+    fn mmap(&mut self, length: usize) -> io::Result<TransferBuffer> {
+        if !self.use_mmap || !self.capabilities.mmap() {
+            return TransferBuffer::heap(length);
+        }
         let ptr = unsafe {
-            let ptr =
-		if self.use_mmap {
-		    libc::mmap(
-			ptr::null_mut(),
-			length as libc::size_t,
-			libc::PROT_READ | libc::PROT_WRITE,
-			libc::MAP_SHARED,
-			self.handle.as_raw_fd(),
-			0,
-		    )
-		} else {
-		    libc::MAP_FAILED
-		};
-            if ptr == libc::MAP_FAILED {
-                let ptr = libc::malloc(length);
-                if ptr.is_null() {
-                    return Err(io::Error::from_raw_os_error(
-                        nix::errno::Errno::ENOMEM as i32,
-                    ));
-                }
-                return Ok((ptr as *mut u8, Box::new(Self::free)));
-            }
-            ptr
-        } as *mut u8;
-
-        Ok((ptr, Box::new(Self::munmap)))
-    }
-
-    fn free(mem: *mut u8, _size: usize) {
-        unsafe {
-            libc::free(mem as *mut libc::c_void);
+            libc::mmap(
+                ptr::null_mut(),
+                length as libc::size_t,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                self.handle.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return TransferBuffer::heap(length);
         }
+        // usbfs hands out zeroed pages that stay valid until munmap
+        Ok(unsafe { TransferBuffer::from_raw(ptr as *mut u8, length, Box::new(Self::munmap)) })
     }
 
     fn munmap(mem: *mut u8, size: usize) {
@@ -543,11 +1131,39 @@ impl UsbFs {
     /// Send a async transfer
     /// It is up to the enduser to poll the file descriptor for a result.
     fn submit_urb(&mut self, urb: Box<UsbFsUrb>) -> io::Result<i32> {
+        self.try_submit_urb(urb).map_err(|(err, urb)| {
+            // Kernel did not take it so give back the transfer memory
+            drop(transfer_from_urb(*urb));
+            err
+        })
+    }
+
+    /// Submit urb, it is given back with the error if the kernel did not take it
+    fn try_submit_urb(
+        &mut self,
+        urb: Box<UsbFsUrb>,
+    ) -> std::result::Result<i32, (io::Error, Box<UsbFsUrb>)> {
+        let buffer = urb.buffer as usize;
         let urb: *mut UsbFsUrb = Box::into_raw(urb);
 
-        let res = unsafe { usb_submit_urb(self.handle.as_raw_fd(), &*urb) }
-            .map_err(|_| io::Error::last_os_error())?;
-        Ok(res)
+        match unsafe { usb_submit_urb(self.handle.as_raw_fd(), &*urb) } {
+            Ok(res) => {
+                self.in_flight.insert(buffer, urb);
+                Ok(res)
+            }
+            Err(_) => Err((self.last_error(), unsafe { Box::from_raw(urb) })),
+        }
+    }
+
+    /// Cancel a submitted URB identified by its buffer address.
+    /// The URB is still given back by async_response with a negative status.
+    pub(crate) fn discard_urb(&mut self, buffer: usize) -> io::Result<()> {
+        let urb = self
+            .in_flight
+            .get(&buffer)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "No URB in flight for this transfer"))?;
+        unsafe { usb_discard_urb(self.handle.as_raw_fd(), *urb) }.map_err(|_| self.last_error())?;
+        Ok(())
     }
 
     /// Submit a new bulk transfer this will not block.
@@ -556,7 +1172,7 @@ impl UsbFs {
     /// before submit_bulk.
     /// Example:
     ///
-    /// ```
+    /// ```ignore
     /// bulk_out.flush()?;
     /// bulk.write_all(&b"HELLO\n")?;
     /// usb.submit_bulk(bulk);
@@ -565,27 +1181,116 @@ impl UsbFs {
     /// match resp { // do stuff }
     /// ```
     pub fn submit_bulk(&mut self, bulk: BulkTransfer) -> io::Result<i32> {
+        self.try_submit_bulk(bulk).map_err(|(err, _)| err)
+    }
+
+    /// submit_bulk() that gives the transfer back with the error when it
+    /// could not be submitted
+    pub(crate) fn try_submit_bulk(
+        &mut self,
+        mut bulk: BulkTransfer,
+    ) -> std::result::Result<i32, (io::Error, BulkTransfer)> {
+        if let Err(err) = self.check_submit_bulk(&bulk) {
+            return Err((err, bulk));
+        }
+        bulk.info_mut().submit();
+        let urb = Box::new(UsbFsUrb::from(bulk));
+        self.try_submit_urb(urb).map_err(|(err, urb)| {
+            let bulk = bulk_from_urb(*urb).expect("URB was made from a bulk transfer");
+            (err, bulk)
+        })
+    }
+
+    fn check_submit_bulk(&self, bulk: &BulkTransfer) -> io::Result<()> {
+        self.connected()?;
         if bulk.actual_length != 0 {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
                 "Make sure call flush() before call submit bulk when reuse a transfer.",
             ));
         }
-        let urb = Box::new(UsbFsUrb::from(bulk));
-        self.submit_urb(urb)
+        self.check_flags(bulk)
+    }
+
+    /// Submit a bulk transfer and call callback with it when it completes,
+    /// from async_response() or async_response_all(). The status is in
+    /// bulk.status. Returning Some(bulk) from the callback resubmits it
+    /// with the same callback, None ends it. If the resubmit fails the
+    /// transfer is returned by async_response() without its callback and
+    /// with the negative errno as status.
+    /// Example:
+    /// ```ignore
+    /// let bulk = usb.new_bulk_in(1, 64)?;
+    /// usb.submit_bulk_with(bulk, move |bulk| {
+    ///     if bulk.status != 0 {
+    ///         return None;
+    ///     }
+    ///     println!("{:?}", &bulk[..]);
+    ///     Some(bulk)
+    /// })?;
+    /// loop {
+    ///     poll.poll(&mut events, None)?;
+    ///     usb.async_response_all()?;
+    /// }
+    /// ```
+    pub fn submit_bulk_with<F>(&mut self, bulk: BulkTransfer, callback: F) -> io::Result<i32>
+    where
+        F: FnMut(BulkTransfer) -> Option<BulkTransfer> + Send + 'static,
+    {
+        self.submit_bulk_callback(bulk, Box::new(callback))
+    }
+
+    fn submit_bulk_callback(
+        &mut self,
+        bulk: BulkTransfer,
+        callback: BulkCallback,
+    ) -> io::Result<i32> {
+        let buffer = bulk.buffer_address();
+        self.callbacks.insert(buffer, callback);
+        let result = self.submit_bulk(bulk);
+        if result.is_err() {
+            self.callbacks.remove(&buffer);
+        }
+        result
+    }
+
+    fn check_flags(&self, bulk: &BulkTransfer) -> io::Result<()> {
+        if bulk.zero_packet() {
+            if !bulk.endpoint.is_bulk_out() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Zero packet can only be set on bulk out",
+                ));
+            }
+            if !self.capabilities.zero_packet() {
+                return Err(Error::new(
+                    ErrorKind::Unsupported,
+                    "Kernel does not support USBFS_URB_FLAGS_ZERO_PACKET",
+                ));
+            }
+        }
+        if bulk.short_not_ok() && !bulk.endpoint.is_bulk_in() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Short not ok can only be set on bulk in",
+            ));
+        }
+        Ok(())
     }
 
     /// Submit a new control transfer this will not block.
     /// One shall call mio poll and async_response(_all) after this call to get the transfer back
     /// Note that if the transfer is reused the user must call flush() before pass it to
     /// submit_control.
-    pub fn submit_control(&mut self, control: ControlTransfer) -> io::Result<i32> {
+    pub fn submit_control(&mut self, mut control: ControlTransfer) -> io::Result<i32> {
+        self.connected()?;
         if control.actual_length != 0 {
             return Err(Error::new(
                 ErrorKind::InvalidInput,
                 "Make sure call flush() before call submit_control when reuse a transfer.",
             ));
         }
+        control.info_mut().submit();
         let urb = Box::new(UsbFsUrb::from(control));
         self.submit_urb(urb)
     }
@@ -593,36 +1298,363 @@ impl UsbFs {
     /// Wait for control response up to timeout ms.
     /// If it find other transfers those are stored in transfers
     /// and can be read using responses()
+    /// A timeout of 0 waits until the transfer completes, 0.4 and earlier
+    /// gave up with TimedOut right after submit.
     pub fn control_async_wait(
         &mut self,
         ctrl: ControlTransfer,
         timeout_ms: TimeoutMillis,
     ) -> io::Result<ControlTransfer> {
-        let timeout_ms = timeout_ms.0;
-        self.submit_control(ctrl)?;
-        let instant = Instant::now();
+        let buffer = ctrl.buffer_address();
+        self.submit_control_timeout(ctrl, timeout_ms)?;
         loop {
             match self.async_response() {
                 Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    std::thread::sleep(Duration::from_micros(1));
+                    self.wait_for_urb(self.next_deadline())?;
                 }
                 Err(e) => {
                     return Err(e);
                 }
-                Ok(transfer) => match transfer {
-                    TransferKind::Control(control) => {
-                        return Ok(control);
+                Ok(TransferKind::Control(control)) if control.buffer_address() == buffer => {
+                    if control.status == -libc::ETIMEDOUT {
+                        return Err(Error::new(ErrorKind::TimedOut, ""));
                     }
-                    _ => self.transfers.push(transfer),
-                },
+                    return Ok(control);
+                }
+                Ok(transfer) => self.transfers.push(transfer),
+            }
+        }
+    }
+
+    /// Submit a bulk transfer that is discarded if not completed within
+    /// timeout. It is given back by async_response with status -ETIMEDOUT.
+    /// A timeout of 0 never expires.
+    pub fn submit_bulk_timeout(
+        &mut self,
+        bulk: BulkTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<i32> {
+        let buffer = bulk.buffer_address();
+        let res = self.submit_bulk(bulk)?;
+        self.set_deadline(buffer, timeout)?;
+        Ok(res)
+    }
+
+    /// Control version of submit_bulk_timeout()
+    pub fn submit_control_timeout(
+        &mut self,
+        control: ControlTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<i32> {
+        let buffer = control.buffer_address();
+        let res = self.submit_control(control)?;
+        self.set_deadline(buffer, timeout)?;
+        Ok(res)
+    }
+
+    fn set_deadline(&mut self, buffer: usize, timeout: TimeoutMillis) -> io::Result<()> {
+        if timeout.0 == 0 {
+            return Ok(());
+        }
+        let deadline = Instant::now() + Duration::from_millis(timeout.0 as u64);
+        self.deadlines.insert(buffer, deadline);
+        self.arm_timer()
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+
+    /// timerfd that is readable when a transfer deadline has passed,
+    /// poll it next to the device and call async_response_all() when
+    /// readable. Created on first use.
+    pub fn timer_fd(&mut self) -> io::Result<RawFd> {
+        if let Some(timer) = &self.timer {
+            return Ok(timer.as_raw_fd());
+        }
+        let fd = unsafe {
+            libc::timerfd_create(
+                libc::CLOCK_MONOTONIC,
+                libc::TFD_NONBLOCK | libc::TFD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.timer = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+        self.arm_timer()?;
+        Ok(fd)
+    }
+
+    /// Set the timer to the next deadline or disarm it
+    fn arm_timer(&self) -> io::Result<()> {
+        let timer = match &self.timer {
+            Some(timer) => timer,
+            None => return Ok(()),
+        };
+        let value = match self.next_deadline() {
+            // Zero would disarm the timer
+            Some(deadline) => deadline
+                .saturating_duration_since(Instant::now())
+                .max(Duration::from_nanos(1)),
+            None => Duration::ZERO,
+        };
+        let spec = libc::itimerspec {
+            it_interval: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            it_value: libc::timespec {
+                tv_sec: value.as_secs() as libc::time_t,
+                tv_nsec: value.subsec_nanos() as libc::c_long,
+            },
+        };
+        if unsafe { libc::timerfd_settime(timer.as_raw_fd(), 0, &spec, ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Discard URBs past their deadline, done by async_response()
+    fn expire_transfers(&mut self) -> io::Result<()> {
+        if let Some(timer) = &self.timer {
+            // Clear readable, fails with EAGAIN if the timer has not fired
+            let mut expirations = [0u8; 8];
+            unsafe { libc::read(timer.as_raw_fd(), expirations.as_mut_ptr() as *mut _, 8) };
+        }
+        if self.deadlines.is_empty() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        let expired: Vec<usize> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(buffer, _)| *buffer)
+            .collect();
+        for buffer in expired {
+            self.deadlines.remove(&buffer);
+            // Fails if it already completed and waits to be reaped
+            if self.discard_urb(buffer).is_ok() {
+                self.timed_out.insert(buffer);
             }
-            if instant.elapsed() >= Duration::from_millis(timeout_ms as u64) {
-                return Err(Error::new(ErrorKind::TimedOut, ""));
+        }
+        self.arm_timer()
+    }
+
+    /// Block until a URB can be reaped, the device is gone or deadline
+    fn wait_for_urb(&self, deadline: Option<Instant>) -> io::Result<()> {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                // Round up so we do not spin on sub millisecond leftovers
+                ((left.as_micros() + 999) / 1000).min(i32::MAX as u128) as i32
             }
+            None => -1,
+        };
+        let mut fds = [PollFd::new(self.handle.as_raw_fd(), PollFlags::POLLOUT)];
+        match poll(&mut fds, timeout) {
+            Ok(_) | Err(nix::errno::Errno::EINTR) => Ok(()),
+            Err(_) => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Chapter 9 standard requests.
+/// SET_ADDRESS, SET_SEL and SET_ISOCH_DELAY are owned by the host controller
+/// driver and can not be sent from user space.
+impl UsbFs {
+    fn standard_in(
+        &mut self,
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> io::Result<Vec<u8>> {
+        let request_type = RequestType::new().input().with_recipient(recipient);
+        let ctrl = self.new_control_in(request_type.into(), request, value, index, length)?;
+        let ctrl = self.control_async_wait(ctrl, TimeoutMillis(STANDARD_REQUEST_TIMEOUT_MS))?;
+        if ctrl.status != 0 {
+            return Err(io::Error::from_raw_os_error(-ctrl.status));
+        }
+        Ok(ctrl.as_slice().to_vec())
+    }
+
+    fn standard_out(
+        &mut self,
+        recipient: Recipient,
+        request: u8,
+        value: u16,
+        index: u16,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let request_type = RequestType::new().output().with_recipient(recipient);
+        let ctrl = self.new_control_out(request_type.into(), request, value, index, data)?;
+        let ctrl = self.control_async_wait(ctrl, TimeoutMillis(STANDARD_REQUEST_TIMEOUT_MS))?;
+        if ctrl.status != 0 {
+            return Err(io::Error::from_raw_os_error(-ctrl.status));
+        }
+        Ok(())
+    }
+
+    /// GET_STATUS, index is the interface or endpoint address
+    /// or 0 for the device.
+    pub fn get_status(&mut self, recipient: Recipient, index: u16) -> io::Result<u16> {
+        let data = self.standard_in(recipient, REQUEST_GET_STATUS, 0, index, 2)?;
+        match data[..] {
+            [low, high] => Ok(u16::from_le_bytes([low, high])),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "GET_STATUS did not return 2 bytes",
+            )),
+        }
+    }
+
+    pub fn set_feature(
+        &mut self,
+        recipient: Recipient,
+        feature: u16,
+        index: u16,
+    ) -> io::Result<()> {
+        self.standard_out(recipient, REQUEST_SET_FEATURE, feature, index, &[])
+    }
+
+    pub fn clear_feature(
+        &mut self,
+        recipient: Recipient,
+        feature: u16,
+        index: u16,
+    ) -> io::Result<()> {
+        self.standard_out(recipient, REQUEST_CLEAR_FEATURE, feature, index, &[])
+    }
+
+    /// Stall endpoint ep, use clear_halt() to clear it since that also
+    /// resets the host side data toggle.
+    pub fn set_halt(&mut self, ep: u8) -> io::Result<()> {
+        self.set_feature(Recipient::Endpoint, FEATURE_ENDPOINT_HALT, ep as u16)
+    }
+
+    pub fn is_halted(&mut self, ep: u8) -> io::Result<bool> {
+        let status = self.get_status(Recipient::Endpoint, ep as u16)?;
+        Ok(status & STATUS_ENDPOINT_HALT != 0)
+    }
+
+    pub fn set_remote_wakeup(&mut self, enable: bool) -> io::Result<()> {
+        self.device_feature(FEATURE_DEVICE_REMOTE_WAKEUP, enable)
+    }
+
+    /// SuperSpeed U1 link state, only valid when configured
+    pub fn set_u1_enable(&mut self, enable: bool) -> io::Result<()> {
+        self.device_feature(FEATURE_U1_ENABLE, enable)
+    }
+
+    /// SuperSpeed U2 link state, only valid when configured
+    pub fn set_u2_enable(&mut self, enable: bool) -> io::Result<()> {
+        self.device_feature(FEATURE_U2_ENABLE, enable)
+    }
+
+    /// Put the device in a test mode, selector 1..=5 (Test_J .. Test_Force_Enable).
+    /// The device must be power cycled to leave test mode.
+    pub fn set_test_mode(&mut self, selector: u8) -> io::Result<()> {
+        self.set_feature(Recipient::Device, FEATURE_TEST_MODE, (selector as u16) << 8)
+    }
+
+    fn device_feature(&mut self, feature: u16, enable: bool) -> io::Result<()> {
+        if enable {
+            self.set_feature(Recipient::Device, feature, 0)
+        } else {
+            self.clear_feature(Recipient::Device, feature, 0)
+        }
+    }
+
+    pub fn get_descriptor(
+        &mut self,
+        descriptor_type: u8,
+        index: u8,
+        language: u16,
+        length: u16,
+    ) -> io::Result<Vec<u8>> {
+        self.standard_in(
+            Recipient::Device,
+            REQUEST_GET_DESCRIPTOR,
+            (descriptor_type as u16) << 8 | index as u16,
+            language,
+            length,
+        )
+    }
+
+    pub fn set_descriptor(
+        &mut self,
+        descriptor_type: u8,
+        index: u8,
+        language: u16,
+        data: &[u8],
+    ) -> io::Result<()> {
+        self.standard_out(
+            Recipient::Device,
+            REQUEST_SET_DESCRIPTOR,
+            (descriptor_type as u16) << 8 | index as u16,
+            language,
+            data,
+        )
+    }
+
+    /// Active bConfigurationValue, 0 if not configured
+    pub fn get_configuration(&mut self) -> io::Result<u8> {
+        let data = self.standard_in(Recipient::Device, REQUEST_GET_CONFIGURATION, 0, 0, 1)?;
+        data.first().copied().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "GET_CONFIGURATION did not return any data",
+            )
+        })
+    }
+
+    /// Select configuration using USBDEVFS_SETCONFIGURATION so the kernel
+    /// rebinds interfaces, 0 unconfigures the device.
+    /// Fails with EBUSY if any interface is claimed.
+    pub fn set_configuration(&mut self, configuration: u8) -> io::Result<()> {
+        let configuration = configuration as u32;
+        unsafe { usb_set_configuration(self.handle.as_raw_fd(), &configuration) }
+            .map_err(|_| self.last_error())?;
+        self.descriptors = None;
+        Ok(())
+    }
+
+    /// Active alternate setting of interface
+    pub fn get_interface(&mut self, interface: u16) -> io::Result<u8> {
+        let data =
+            self.standard_in(Recipient::Interface, REQUEST_GET_INTERFACE, 0, interface, 1)?;
+        data.first().copied().ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidData,
+                "GET_INTERFACE did not return any data",
+            )
+        })
+    }
+
+    /// Frame number of the isochronous endpoint ep pattern
+    pub fn synch_frame(&mut self, ep: u8) -> io::Result<u16> {
+        let data = self.standard_in(Recipient::Endpoint, REQUEST_SYNCH_FRAME, 0, ep as u16, 2)?;
+        match data[..] {
+            [low, high] => Ok(u16::from_le_bytes([low, high])),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "SYNCH_FRAME did not return 2 bytes",
+            )),
         }
     }
 }
 
+impl FromRawFd for UsbFs {
+    /// # Safety
+    /// fd must be an open usbfs device node owned by the caller
+    unsafe fn from_raw_fd(fd: RawFd) -> UsbFs {
+        UsbFs::from_open_file(std::fs::File::from_raw_fd(fd))
+    }
+}
+
 impl Drop for UsbFs {
     fn drop(&mut self) {
         for claim in &self.claims {
@@ -630,3 +1662,33 @@ impl Drop for UsbFs {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    #[test]
+    fn failed_callback_resubmit_gives_back_transfer() {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .unwrap();
+        let mut usb = UsbFs::from_file(file).unwrap();
+        usb.set_use_mmap(false);
+        let bulk = usb.new_bulk_in(1, 64).unwrap();
+        let buffer = bulk.buffer_address();
+        usb.callbacks.insert(buffer, Box::new(Some));
+
+        let transfer = usb.dispatch(TransferKind::Bulk(bulk)).unwrap();
+        match transfer {
+            Some(TransferKind::Bulk(bulk)) => {
+                assert_eq!(bulk.buffer_address(), buffer);
+                assert_eq!(bulk.status, -libc::ENOTTY);
+            }
+            _ => panic!("Transfer not given back"),
+        }
+        assert!(usb.callbacks.is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 use super::constants::*;
 use crate::endpoint::Endpoint;
-use crate::usb_transfer::{BufferSlice, BulkTransfer, ControlTransfer};
+use crate::usb_transfer::{BulkTransfer, ControlTransfer, TransferKind};
 use std::fmt;
 use std::io;
 use std::io::{Error, ErrorKind};
@@ -40,6 +40,11 @@ impl UsbFsUrb {
             usercontext: user,
         }
     }
+
+    pub(crate) fn with_flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
 }
 
 impl fmt::Display for UsbFsUrb {
@@ -62,27 +67,38 @@ impl From<BulkTransfer> for UsbFsUrb {
     fn from(bulk: BulkTransfer) -> Self {
         // Box make sure BulkTransfer is not deallocated
         // when when convert to raw below.
-        let bulk = Box::new(bulk);
+        let mut bulk = Box::new(bulk);
+        let mut flags = 0;
+        if bulk.zero_packet() {
+            flags |= USBFS_URB_FLAGS_ZERO_PACKET;
+        }
+        if bulk.short_not_ok() {
+            flags |= USBFS_URB_FLAGS_SHORT_NOT_OK;
+        }
+        if bulk.no_interrupt() {
+            flags |= USBFS_URB_FLAGS_NO_INTERRUPT;
+        }
         UsbFsUrb::new(
             USBFS_URB_TYPE_BULK,
             bulk.endpoint,
-            bulk.buffer,
+            bulk.buffer_mut_ptr(),
             bulk.buffer_length,
             // BulkTransfer is may in theory leak if not passed to kernel
             // to used space.
             Box::into_raw(bulk) as *mut u8,
         )
+        .with_flags(flags)
     }
 }
 
 impl From<ControlTransfer> for UsbFsUrb {
     fn from(ctrl: ControlTransfer) -> Self {
         // prevent it from getting freed
-        let ctrl = Box::new(ctrl);
+        let mut ctrl = Box::new(ctrl);
         UsbFsUrb::new(
             USBFS_URB_TYPE_CONTROL,
             Endpoint::new(0),
-            ctrl.buffer,
+            ctrl.buffer_mut_ptr(),
             ctrl.buffer_length as usize,
             // this is now "leaked" until transfered to kernel and back
             // to used space. using Box::from_raw on control_from_urb
@@ -119,6 +135,8 @@ pub(crate) fn bulk_from_urb(urb: UsbFsUrb) -> io::Result<BulkTransfer> {
     // in the usbapi-rs and we beter fix those errors.
     assert!(urb.buffer_length as usize == bulk.buffer_length);
     bulk.actual_length = urb.actual_length as usize;
+    bulk.status = urb.status;
+    bulk.info_mut().complete(urb.error_count, urb.start_frame);
     // if actual is bigger than we asked kernel to store something is smoking
     assert!(bulk.actual_length <= bulk.buffer_length);
     Ok(bulk)
@@ -142,37 +160,22 @@ pub(crate) fn control_from_urb(urb: UsbFsUrb) -> io::Result<ControlTransfer> {
     let mut control = *unsafe { Box::from_raw(urb.usercontext as *mut ControlTransfer) };
     assert!(urb.buffer_length as u16 == control.buffer_length);
     control.actual_length = urb.actual_length as u16;
+    control.status = urb.status;
+    control
+        .info_mut()
+        .complete(urb.error_count, urb.start_frame);
     assert!(control.actual_length <= control.buffer_length);
     Ok(control)
 }
 
-impl BufferSlice for BulkTransfer {
-    /// return buffer slice from raw pointer
-    /// panic in case actual_length or buffer_capacity is incorrect
-    /// Should not happen but if it does we should fix the broken code.
-    /// return empty slice if length is 0
-    fn buffer_from_raw<'a>(&self) -> &'a [u8] {
-        if (self.endpoint.is_bulk_in() && self.actual_length == 0)
-            || (self.endpoint.is_bulk_out() && self.buffer_length == 0)
-        {
-            return &[];
-        }
-        assert!(self.actual_length <= self.buffer_length);
-        assert!(self.buffer_length <= self.buffer_capacity);
-        if self.endpoint.is_bulk_in() {
-            unsafe { std::slice::from_raw_parts(self.buffer, self.actual_length as usize) }
-        } else {
-            unsafe { std::slice::from_raw_parts(self.buffer, self.buffer_length as usize) }
-        }
-    }
-}
-
-impl BufferSlice for ControlTransfer {
-    fn buffer_from_raw<'a>(&self) -> &'a [u8] {
-        if self.actual_length == 0 {
-            return &[];
-        }
-        assert!(self.actual_length <= self.buffer_length - 8);
-        unsafe { std::slice::from_raw_parts(self.buffer.offset(8), self.actual_length as usize) }
+/// Give back the transfer owned by urb.usercontext
+pub(crate) fn transfer_from_urb(urb: UsbFsUrb) -> io::Result<TransferKind> {
+    let ep = Endpoint::new(urb.endpoint);
+    if ep.is_bulk() {
+        Ok(TransferKind::Bulk(bulk_from_urb(urb)?))
+    } else if ep.is_control() {
+        Ok(TransferKind::Control(control_from_urb(urb)?))
+    } else {
+        Ok(TransferKind::Invalid(ep))
     }
 }
@@ -0,0 +1,763 @@
+//! Read and write USB captures in pcap and pcapng format.
+//!
+//! Packets use link type 220 (LINKTYPE_USB_LINUX_MMAPPED) which is the same
+//! 64 byte header the Linux usbmon binary interface hands out, so files can
+//! be opened in Wireshark or produced from /dev/usbmonN reads.
+use crate::usb_transfer::{BulkTransfer, ControlTransfer};
+use crate::ENDPOINT_IN;
+use std::fmt;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const LINKTYPE_USB_LINUX_MMAPPED: u32 = 220;
+
+pub const EVENT_SUBMIT: u8 = b'S';
+pub const EVENT_COMPLETE: u8 = b'C';
+pub const EVENT_ERROR: u8 = b'E';
+
+pub const TRANSFER_TYPE_ISO: u8 = 0;
+pub const TRANSFER_TYPE_INTERRUPT: u8 = 1;
+pub const TRANSFER_TYPE_CONTROL: u8 = 2;
+pub const TRANSFER_TYPE_BULK: u8 = 3;
+
+// setup_flag is 0 when setup holds a setup packet, data_flag is 0 when
+// data is present. Otherwise usbmon puts one of these characters there.
+const FLAG_SETUP_ABSENT: u8 = b'-';
+const FLAG_DATA_IN_ABSENT: u8 = b'<';
+const FLAG_DATA_OUT_ABSENT: u8 = b'>';
+
+const PCAP_MAGIC_USEC: u32 = 0xa1b2_c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b2_3c4d;
+const PCAP_SNAPLEN: u32 = 0x0004_0000;
+const PCAPNG_SHB: u32 = 0x0A0D_0D0A;
+const PCAPNG_IDB: u32 = 0x0000_0001;
+const PCAPNG_SPB: u32 = 0x0000_0003;
+const PCAPNG_EPB: u32 = 0x0000_0006;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+// Largest record or block read from a file, same as Wireshark
+const MAX_BLOCK_LEN: usize = 16 * 1024 * 1024;
+
+/// Size of the usbmon mmapped header.
+pub const USB_HEADER_SIZE: usize = 64;
+/// Size of each isochronous descriptor following the header.
+pub const ISO_DESCRIPTOR_SIZE: usize = 16;
+
+/// The usbmon binary header (struct mon_bin_hdr).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsbPacketHeader {
+    /// URB id, the same for the submit and complete of one transfer
+    pub id: u64,
+    /// EVENT_SUBMIT, EVENT_COMPLETE or EVENT_ERROR
+    pub event_type: u8,
+    /// TRANSFER_TYPE_*
+    pub transfer_type: u8,
+    /// Endpoint address including the direction bit
+    pub endpoint: u8,
+    pub device_address: u8,
+    pub bus_id: u16,
+    pub setup_flag: u8,
+    pub data_flag: u8,
+    pub ts_sec: i64,
+    pub ts_usec: i32,
+    pub status: i32,
+    /// Requested length on submit, actual length on complete
+    pub urb_len: u32,
+    /// Length of data captured after the header
+    pub data_len: u32,
+    /// Setup packet for control, (error_count, numdesc) for iso
+    pub setup: [u8; 8],
+    pub interval: i32,
+    pub start_frame: i32,
+    pub xfer_flags: u32,
+    pub ndesc: u32,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IsoDescriptor {
+    pub status: i32,
+    pub offset: u32,
+    pub length: u32,
+}
+
+/// One captured usbmon event.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsbPacket {
+    pub header: UsbPacketHeader,
+    pub iso_descriptors: Vec<IsoDescriptor>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Endian {
+    Little,
+    Big,
+}
+
+impl Endian {
+    fn u16(self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        match self {
+            Endian::Little => u16::from_le_bytes(b),
+            Endian::Big => u16::from_be_bytes(b),
+        }
+    }
+
+    fn u32(self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        match self {
+            Endian::Little => u32::from_le_bytes(b),
+            Endian::Big => u32::from_be_bytes(b),
+        }
+    }
+
+    fn u64(self, b: &[u8]) -> u64 {
+        let mut x = [0; 8];
+        x.copy_from_slice(&b[..8]);
+        match self {
+            Endian::Little => u64::from_le_bytes(x),
+            Endian::Big => u64::from_be_bytes(x),
+        }
+    }
+}
+
+fn invalid_data<E>(error: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::new(ErrorKind::InvalidData, error)
+}
+
+fn now() -> (i64, i32) {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (ts.as_secs() as i64, ts.subsec_micros() as i32)
+}
+
+impl fmt::Display for UsbPacketHeader {
+    /// Same layout as the usbmon text interface
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:016x} {}.{:06} {} {}{}:{}:{:03}:{} {} {}",
+            self.id,
+            self.ts_sec,
+            self.ts_usec,
+            self.event_type as char,
+            match self.transfer_type {
+                TRANSFER_TYPE_ISO => 'Z',
+                TRANSFER_TYPE_INTERRUPT => 'I',
+                TRANSFER_TYPE_CONTROL => 'C',
+                _ => 'B',
+            },
+            if self.endpoint & ENDPOINT_IN != 0 {
+                'i'
+            } else {
+                'o'
+            },
+            self.bus_id,
+            self.device_address,
+            self.endpoint & 0x7F,
+            self.status,
+            self.urb_len
+        )
+    }
+}
+
+impl UsbPacketHeader {
+    fn parse(bytes: &[u8], endian: Endian) -> io::Result<Self> {
+        if bytes.len() < USB_HEADER_SIZE {
+            return Err(invalid_data(format!(
+                "USB header needs {} bytes got {}",
+                USB_HEADER_SIZE,
+                bytes.len()
+            )));
+        }
+        let mut setup = [0; 8];
+        setup.copy_from_slice(&bytes[40..48]);
+        Ok(UsbPacketHeader {
+            id: endian.u64(&bytes[0..]),
+            event_type: bytes[8],
+            transfer_type: bytes[9],
+            endpoint: bytes[10],
+            device_address: bytes[11],
+            bus_id: endian.u16(&bytes[12..]),
+            setup_flag: bytes[14],
+            data_flag: bytes[15],
+            ts_sec: endian.u64(&bytes[16..]) as i64,
+            ts_usec: endian.u32(&bytes[24..]) as i32,
+            status: endian.u32(&bytes[28..]) as i32,
+            urb_len: endian.u32(&bytes[32..]),
+            data_len: endian.u32(&bytes[36..]),
+            setup,
+            interval: endian.u32(&bytes[48..]) as i32,
+            start_frame: endian.u32(&bytes[52..]) as i32,
+            xfer_flags: endian.u32(&bytes[56..]),
+            ndesc: endian.u32(&bytes[60..]),
+        })
+    }
+
+    /// Parse a little endian header as read from /dev/usbmonN on x86/ARM.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        Self::parse(bytes, Endian::Little)
+    }
+
+    /// Serialize the header as little endian.
+    pub fn to_bytes(&self) -> [u8; USB_HEADER_SIZE] {
+        let mut b = [0; USB_HEADER_SIZE];
+        b[0..8].copy_from_slice(&self.id.to_le_bytes());
+        b[8] = self.event_type;
+        b[9] = self.transfer_type;
+        b[10] = self.endpoint;
+        b[11] = self.device_address;
+        b[12..14].copy_from_slice(&self.bus_id.to_le_bytes());
+        b[14] = self.setup_flag;
+        b[15] = self.data_flag;
+        b[16..24].copy_from_slice(&self.ts_sec.to_le_bytes());
+        b[24..28].copy_from_slice(&self.ts_usec.to_le_bytes());
+        b[28..32].copy_from_slice(&self.status.to_le_bytes());
+        b[32..36].copy_from_slice(&self.urb_len.to_le_bytes());
+        b[36..40].copy_from_slice(&self.data_len.to_le_bytes());
+        b[40..48].copy_from_slice(&self.setup);
+        b[48..52].copy_from_slice(&self.interval.to_le_bytes());
+        b[52..56].copy_from_slice(&self.start_frame.to_le_bytes());
+        b[56..60].copy_from_slice(&self.xfer_flags.to_le_bytes());
+        b[60..64].copy_from_slice(&self.ndesc.to_le_bytes());
+        b
+    }
+}
+
+impl fmt::Display for UsbPacket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.header)?;
+        if !self.data.is_empty() {
+            write!(f, " =")?;
+            for chunk in self.data.chunks(4) {
+                write!(f, " ")?;
+                for b in chunk {
+                    write!(f, "{:02x}", b)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl UsbPacket {
+    fn parse(bytes: &[u8], endian: Endian) -> io::Result<Self> {
+        let header = UsbPacketHeader::parse(bytes, endian)?;
+        let mut offset = USB_HEADER_SIZE;
+        let mut iso_descriptors = Vec::new();
+        for _ in 0..header.ndesc {
+            if bytes.len() < offset + ISO_DESCRIPTOR_SIZE {
+                return Err(invalid_data("Truncated isochronous descriptor"));
+            }
+            iso_descriptors.push(IsoDescriptor {
+                status: endian.u32(&bytes[offset..]) as i32,
+                offset: endian.u32(&bytes[offset + 4..]),
+                length: endian.u32(&bytes[offset + 8..]),
+            });
+            offset += ISO_DESCRIPTOR_SIZE;
+        }
+        // Data may have been cut by the snap length so trust what is there
+        let end = bytes.len().min(offset + header.data_len as usize);
+        Ok(UsbPacket {
+            header,
+            iso_descriptors,
+            data: bytes[offset..end].to_vec(),
+        })
+    }
+
+    /// Parse a packet (header, iso descriptors and data) in little endian.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        Self::parse(bytes, Endian::Little)
+    }
+
+    /// Serialize the packet as little endian.
+    /// header.data_len and header.ndesc are taken from data and iso_descriptors.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = self.header.clone();
+        header.data_len = self.data.len() as u32;
+        header.ndesc = self.iso_descriptors.len() as u32;
+        let mut bytes = Vec::with_capacity(self.encoded_len());
+        bytes.extend_from_slice(&header.to_bytes());
+        for iso in &self.iso_descriptors {
+            bytes.extend_from_slice(&iso.status.to_le_bytes());
+            bytes.extend_from_slice(&iso.offset.to_le_bytes());
+            bytes.extend_from_slice(&iso.length.to_le_bytes());
+            bytes.extend_from_slice(&[0; 4]);
+        }
+        bytes.extend_from_slice(&self.data);
+        bytes
+    }
+
+    /// Length of the serialized packet
+    pub fn encoded_len(&self) -> usize {
+        USB_HEADER_SIZE + self.iso_descriptors.len() * ISO_DESCRIPTOR_SIZE + self.data.len()
+    }
+
+    /// Create a packet from a bulk transfer.
+    /// Use EVENT_SUBMIT before passing it to submit_bulk and EVENT_COMPLETE
+    /// when it is given back by async_response.
+    pub fn from_bulk(event_type: u8, bus: u8, dev: u8, bulk: &BulkTransfer) -> Self {
+        let (ts_sec, ts_usec) = now();
+        let is_in = bulk.endpoint.is_bulk_in();
+        let submit = event_type == EVENT_SUBMIT;
+        // Only OUT data on submit and IN data on complete is of interest
+        let data = if is_in != submit {
+            bulk.as_slice().to_vec()
+        } else {
+            vec![]
+        };
+        UsbPacket {
+            header: UsbPacketHeader {
+                // The buffer survives the round trip through the kernel
+                id: bulk.buffer_address() as u64,
+                event_type,
+                transfer_type: TRANSFER_TYPE_BULK,
+                endpoint: bulk.endpoint.into(),
+                device_address: dev,
+                bus_id: bus as u16,
+                setup_flag: FLAG_SETUP_ABSENT,
+                data_flag: match (data.is_empty(), is_in) {
+                    (false, _) => 0,
+                    (true, true) => FLAG_DATA_IN_ABSENT,
+                    (true, false) => FLAG_DATA_OUT_ABSENT,
+                },
+                ts_sec,
+                ts_usec,
+                status: if submit {
+                    -libc::EINPROGRESS
+                } else {
+                    bulk.status
+                },
+                urb_len: if submit {
+                    bulk.buffer_length as u32
+                } else {
+                    bulk.actual_length as u32
+                },
+                data_len: data.len() as u32,
+                ..Default::default()
+            },
+            iso_descriptors: vec![],
+            data,
+        }
+    }
+
+    /// Create a packet from a control transfer see from_bulk.
+    pub fn from_control(event_type: u8, bus: u8, dev: u8, control: &ControlTransfer) -> Self {
+        let (ts_sec, ts_usec) = now();
+        let setup = control.setup_packet();
+        let is_in = setup[0] & ENDPOINT_IN != 0;
+        let submit = event_type == EVENT_SUBMIT;
+        let data = match (submit, is_in) {
+            (true, false) => control.request_data().to_vec(),
+            (false, true) => control.as_slice().to_vec(),
+            _ => vec![],
+        };
+        UsbPacket {
+            header: UsbPacketHeader {
+                id: control.buffer_address() as u64,
+                event_type,
+                transfer_type: TRANSFER_TYPE_CONTROL,
+                endpoint: if is_in { ENDPOINT_IN } else { 0 },
+                device_address: dev,
+                bus_id: bus as u16,
+                setup_flag: if submit { 0 } else { FLAG_SETUP_ABSENT },
+                data_flag: match (data.is_empty(), is_in) {
+                    (false, _) => 0,
+                    (true, true) => FLAG_DATA_IN_ABSENT,
+                    (true, false) => FLAG_DATA_OUT_ABSENT,
+                },
+                ts_sec,
+                ts_usec,
+                status: if submit {
+                    -libc::EINPROGRESS
+                } else {
+                    control.status
+                },
+                urb_len: if submit {
+                    u16::from_le_bytes([setup[6], setup[7]]) as u32
+                } else {
+                    control.actual_length as u32
+                },
+                data_len: data.len() as u32,
+                setup: if submit { setup } else { [0; 8] },
+                ..Default::default()
+            },
+            iso_descriptors: vec![],
+            data,
+        }
+    }
+}
+
+/// The part of a serialized packet that fits in PCAP_SNAPLEN
+fn snap(bytes: &[u8]) -> &[u8] {
+    &bytes[..bytes.len().min(PCAP_SNAPLEN as usize)]
+}
+
+/// Write packets to a classic pcap file.
+///
+/// Example:
+/// ```ignore
+/// let mut pcap = PcapWriter::new(File::create("capture.pcap")?)?;
+/// pcap.write_packet(&UsbPacket::from_bulk(EVENT_SUBMIT, 1, 4, &bulk))?;
+/// ```
+pub struct PcapWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    /// Write the pcap global header
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&PCAP_MAGIC_USEC.to_le_bytes())?;
+        writer.write_all(&2u16.to_le_bytes())?;
+        writer.write_all(&4u16.to_le_bytes())?;
+        // thiszone and sigfigs
+        writer.write_all(&[0; 8])?;
+        writer.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_USB_LINUX_MMAPPED.to_le_bytes())?;
+        Ok(Self { writer })
+    }
+
+    /// Data beyond the snap length is cut like tcpdump does
+    pub fn write_packet(&mut self, packet: &UsbPacket) -> io::Result<()> {
+        let bytes = packet.to_bytes();
+        let captured = snap(&bytes);
+        self.writer
+            .write_all(&(packet.header.ts_sec as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&(packet.header.ts_usec as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&(captured.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+        self.writer.write_all(captured)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Write packets to a pcapng file with one USB interface.
+pub struct PcapNgWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> PcapNgWriter<W> {
+    /// Write the section header and interface description blocks
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        let mut shb = Vec::with_capacity(16);
+        shb.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        // Section length unknown
+        shb.extend_from_slice(&u64::MAX.to_le_bytes());
+        Self::write_block(&mut writer, PCAPNG_SHB, &shb)?;
+
+        let mut idb = Vec::with_capacity(8);
+        idb.extend_from_slice(&(LINKTYPE_USB_LINUX_MMAPPED as u16).to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+        Self::write_block(&mut writer, PCAPNG_IDB, &idb)?;
+        Ok(Self { writer })
+    }
+
+    fn write_block(writer: &mut W, kind: u32, body: &[u8]) -> io::Result<()> {
+        let padding = (4 - body.len() % 4) % 4;
+        let total = (12 + body.len() + padding) as u32;
+        writer.write_all(&kind.to_le_bytes())?;
+        writer.write_all(&total.to_le_bytes())?;
+        writer.write_all(body)?;
+        writer.write_all(&[0; 3][..padding])?;
+        writer.write_all(&total.to_le_bytes())
+    }
+
+    /// Write an enhanced packet block, timestamps use the default
+    /// microsecond resolution. Data beyond the snap length is cut.
+    pub fn write_packet(&mut self, packet: &UsbPacket) -> io::Result<()> {
+        let bytes = packet.to_bytes();
+        let captured = snap(&bytes);
+        let ts = packet.header.ts_sec as u64 * 1_000_000 + packet.header.ts_usec as u64;
+        let mut epb = Vec::with_capacity(20 + captured.len());
+        // interface id
+        epb.extend_from_slice(&0u32.to_le_bytes());
+        epb.extend_from_slice(&((ts >> 32) as u32).to_le_bytes());
+        epb.extend_from_slice(&(ts as u32).to_le_bytes());
+        epb.extend_from_slice(&(captured.len() as u32).to_le_bytes());
+        epb.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        epb.extend_from_slice(captured);
+        Self::write_block(&mut self.writer, PCAPNG_EPB, &epb)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+enum Format {
+    // Records longer than snaplen are rejected
+    Pcap { snaplen: usize },
+    PcapNg { linktypes: Vec<u16> },
+}
+
+/// Read packets from a pcap or pcapng file.
+/// The format and byte order are detected from the file header.
+///
+/// Example:
+/// ```ignore
+/// let reader = PcapReader::new(BufReader::new(File::open("capture.pcapng")?))?;
+/// for packet in reader {
+///     println!("{}", packet?);
+/// }
+/// ```
+pub struct PcapReader<R: Read> {
+    reader: R,
+    endian: Endian,
+    format: Format,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if u32::from_le_bytes(magic) == PCAPNG_SHB {
+            let mut res = Self {
+                reader,
+                endian: Endian::Little,
+                format: Format::PcapNg { linktypes: vec![] },
+            };
+            res.read_section_header()?;
+            return Ok(res);
+        }
+
+        let endian = match magic {
+            m if u32::from_le_bytes(m) == PCAP_MAGIC_USEC
+                || u32::from_le_bytes(m) == PCAP_MAGIC_NSEC =>
+            {
+                Endian::Little
+            }
+            m if u32::from_be_bytes(m) == PCAP_MAGIC_USEC
+                || u32::from_be_bytes(m) == PCAP_MAGIC_NSEC =>
+            {
+                Endian::Big
+            }
+            _ => return Err(invalid_data("Not a pcap or pcapng file")),
+        };
+        let mut header = [0; 20];
+        reader.read_exact(&mut header)?;
+        let snaplen = match endian.u32(&header[12..]) as usize {
+            0 => MAX_BLOCK_LEN,
+            snaplen => snaplen.min(MAX_BLOCK_LEN),
+        };
+        let linktype = endian.u32(&header[16..]);
+        if linktype & 0xFFFF != LINKTYPE_USB_LINUX_MMAPPED {
+            return Err(invalid_data(format!(
+                "Link type {} is not LINKTYPE_USB_LINUX_MMAPPED",
+                linktype
+            )));
+        }
+        Ok(Self {
+            reader,
+            endian,
+            format: Format::Pcap { snaplen },
+        })
+    }
+
+    /// Called after the block type of a section header has been read
+    fn read_section_header(&mut self) -> io::Result<()> {
+        let mut head = [0; 8];
+        self.reader.read_exact(&mut head)?;
+        self.endian = if u32::from_le_bytes([head[4], head[5], head[6], head[7]])
+            == PCAPNG_BYTE_ORDER_MAGIC
+        {
+            Endian::Little
+        } else if u32::from_be_bytes([head[4], head[5], head[6], head[7]])
+            == PCAPNG_BYTE_ORDER_MAGIC
+        {
+            Endian::Big
+        } else {
+            return Err(invalid_data("Invalid pcapng byte order magic"));
+        };
+        let total = self.endian.u32(&head) as usize;
+        if !(28..=MAX_BLOCK_LEN).contains(&total) {
+            return Err(invalid_data("Invalid pcapng section header length"));
+        }
+        // Skip the rest of the block, interfaces are scoped by section
+        let mut rest = vec![0; total - 12];
+        self.reader.read_exact(&mut rest)?;
+        self.format = Format::PcapNg { linktypes: vec![] };
+        Ok(())
+    }
+
+    /// Read the next packet, Ok(None) at end of file.
+    pub fn next_packet(&mut self) -> io::Result<Option<UsbPacket>> {
+        match self.format {
+            Format::Pcap { snaplen } => self.next_pcap(snaplen),
+            Format::PcapNg { .. } => self.next_pcapng(),
+        }
+    }
+
+    /// Read 4 bytes or return None at a clean end of file
+    fn read_u32_or_eof(&mut self) -> io::Result<Option<[u8; 4]>> {
+        let mut b = [0; 4];
+        let mut read = 0;
+        while read < b.len() {
+            match self.reader.read(&mut b[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Some(b))
+    }
+
+    fn next_pcap(&mut self, snaplen: usize) -> io::Result<Option<UsbPacket>> {
+        // The record timestamp is a copy of the one in the USB header
+        if self.read_u32_or_eof()?.is_none() {
+            return Ok(None);
+        }
+        let mut record = [0; 12];
+        self.reader.read_exact(&mut record)?;
+        let incl_len = self.endian.u32(&record[4..]) as usize;
+        if incl_len > snaplen {
+            return Err(invalid_data(format!(
+                "Record length {} is larger than snaplen {}",
+                incl_len, snaplen
+            )));
+        }
+        let mut bytes = vec![0; incl_len];
+        self.reader.read_exact(&mut bytes)?;
+        UsbPacket::parse(&bytes, self.endian).map(Some)
+    }
+
+    fn next_pcapng(&mut self) -> io::Result<Option<UsbPacket>> {
+        loop {
+            let kind = match self.read_u32_or_eof()? {
+                Some(b) => b,
+                None => return Ok(None),
+            };
+            if u32::from_le_bytes(kind) == PCAPNG_SHB {
+                self.read_section_header()?;
+                continue;
+            }
+            let kind = self.endian.u32(&kind);
+            let mut length = [0; 4];
+            self.reader.read_exact(&mut length)?;
+            let total = self.endian.u32(&length) as usize;
+            if !(12..=MAX_BLOCK_LEN).contains(&total) || total % 4 != 0 {
+                return Err(invalid_data(format!(
+                    "Invalid pcapng block length {}",
+                    total
+                )));
+            }
+            let mut body = vec![0; total - 8];
+            self.reader.read_exact(&mut body)?;
+            let body = &body[..total - 12];
+            let linktypes = match &mut self.format {
+                Format::PcapNg { linktypes } => linktypes,
+                Format::Pcap { .. } => unreachable!(),
+            };
+            let (interface, packet) = match kind {
+                PCAPNG_IDB if body.len() >= 8 => {
+                    linktypes.push(self.endian.u16(body));
+                    continue;
+                }
+                PCAPNG_EPB if body.len() >= 20 => {
+                    let captured = self.endian.u32(&body[12..]) as usize;
+                    if body.len() < 20 + captured {
+                        return Err(invalid_data("Truncated enhanced packet block"));
+                    }
+                    (self.endian.u32(body) as usize, &body[20..20 + captured])
+                }
+                PCAPNG_SPB if body.len() >= 4 => {
+                    let original = self.endian.u32(body) as usize;
+                    (0, &body[4..body.len().min(4 + original)])
+                }
+                _ => continue,
+            };
+            match linktypes.get(interface) {
+                Some(&linktype) if linktype as u32 == LINKTYPE_USB_LINUX_MMAPPED => {
+                    return UsbPacket::parse(packet, self.endian).map(Some);
+                }
+                Some(linktype) => {
+                    log::debug!("Skip packet with link type {}", linktype);
+                }
+                None => {
+                    return Err(invalid_data(format!(
+                        "Packet for undeclared interface {}",
+                        interface
+                    )));
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for PcapReader<R> {
+    type Item = io::Result<UsbPacket>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn oversized() -> UsbPacket {
+        UsbPacket {
+            header: UsbPacketHeader {
+                event_type: EVENT_COMPLETE,
+                transfer_type: TRANSFER_TYPE_BULK,
+                endpoint: 0x81,
+                ..Default::default()
+            },
+            iso_descriptors: vec![],
+            data: (0..PCAP_SNAPLEN as usize + 1000).map(|i| i as u8).collect(),
+        }
+    }
+
+    fn check_snapped(read: &UsbPacket, written: &UsbPacket) {
+        let captured = PCAP_SNAPLEN as usize - USB_HEADER_SIZE;
+        assert_eq!(read.header.data_len as usize, written.data.len());
+        assert_eq!(read.data[..], written.data[..captured]);
+    }
+
+    #[test]
+    fn pcap_round_trip_of_oversized_packet() {
+        let packet = oversized();
+        let mut pcap = PcapWriter::new(vec![]).unwrap();
+        pcap.write_packet(&packet).unwrap();
+        let bytes = pcap.into_inner();
+        let orig_len = Endian::Little.u32(&bytes[24 + 12..]);
+        assert_eq!(orig_len as usize, packet.encoded_len());
+
+        let mut reader = PcapReader::new(&bytes[..]).unwrap();
+        check_snapped(&reader.next_packet().unwrap().unwrap(), &packet);
+        assert!(reader.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn pcapng_round_trip_of_oversized_packet() {
+        let packet = oversized();
+        let mut pcapng = PcapNgWriter::new(vec![]).unwrap();
+        pcapng.write_packet(&packet).unwrap();
+        let bytes = pcapng.into_inner();
+
+        let mut reader = PcapReader::new(&bytes[..]).unwrap();
+        check_snapped(&reader.next_packet().unwrap().unwrap(), &packet);
+        assert!(reader.next_packet().unwrap().is_none());
+    }
+}
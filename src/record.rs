@@ -0,0 +1,542 @@
+//! Record blocking transfers to a file and replay them without a device.
+//!
+//! Wrap a UsbCore in a Recorder to log every bulk write, bulk read and
+//! control transfer together with its result and timing. A Replay serves
+//! the same exchanges back through UsbCoreBlocking and flags any request
+//! that differs from what was recorded.
+//!
+//! Each exchange is stored as one line:
+//! ```text
+//! <start_us> <elapsed_us> bulk_write <ep> <data> ok <length> -
+//! <start_us> <elapsed_us> bulk_read <ep> <length> ok <length> <data>
+//! <start_us> <elapsed_us> control <setup> <data> ok <length> <data> <status>
+//! <start_us> <elapsed_us> ... err <errno>
+//! ```
+//! Data is hex encoded and `-` means empty. Lines starting with # are comments.
+//! The control status is 0 or a negative errno like -32 for a stall, it
+//! is 0 when missing.
+use crate::usb_transfer::{BulkTransfer, ControlTransfer, TransferBuffer};
+use crate::{TimeoutMillis, UsbCoreBlocking, UsbCoreDriver, ENDPOINT_IN};
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// What the host asked the device to do
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    BulkWrite { ep: u8, data: Vec<u8> },
+    BulkRead { ep: u8, length: usize },
+    Control { setup: [u8; 8], data: Vec<u8> },
+}
+
+/// What came back from a successful request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Response {
+    /// Bytes transferred
+    pub length: usize,
+    /// Data received from the device, empty for writes
+    pub data: Vec<u8>,
+    /// Status of control transfers, 0 or a negative errno
+    pub status: i32,
+}
+
+/// One recorded exchange
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Time since recording started when the request was issued
+    pub start: Duration,
+    /// How long the request took
+    pub elapsed: Duration,
+    pub request: Request,
+    /// Response or the OS error number
+    pub result: Result<Response, i32>,
+}
+
+/// A request that did not match the recording
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    /// Index of the record that was expected
+    pub index: usize,
+    /// None when the recording has no more records
+    pub expected: Option<Request>,
+    pub got: Request,
+}
+
+fn to_hex(data: &[u8]) -> String {
+    if data.is_empty() {
+        return "-".into();
+    }
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> io::Result<Vec<u8>> {
+    if s == "-" {
+        return Ok(vec![]);
+    }
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err(invalid_data(format!("Invalid hex data: {}", s)));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(invalid_data))
+        .collect()
+}
+
+fn invalid_data<E>(error: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::new(ErrorKind::InvalidData, error)
+}
+
+/// Errors without an OS error number are stored as the closest errno
+fn errno(e: &Error) -> i32 {
+    e.raw_os_error().unwrap_or(match e.kind() {
+        ErrorKind::TimedOut => libc::ETIMEDOUT,
+        ErrorKind::WouldBlock => libc::EAGAIN,
+        ErrorKind::InvalidInput => libc::EINVAL,
+        _ => libc::EIO,
+    })
+}
+
+impl fmt::Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::BulkWrite { ep, data } => write!(f, "bulk_write {:02x} {}", ep, to_hex(data)),
+            Request::BulkRead { ep, length } => write!(f, "bulk_read {:02x} {}", ep, length),
+            Request::Control { setup, data } => {
+                write!(f, "control {} {}", to_hex(setup), to_hex(data))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} ",
+            self.start.as_micros(),
+            self.elapsed.as_micros(),
+            self.request
+        )?;
+        match &self.result {
+            Ok(response) => {
+                write!(f, "ok {} {}", response.length, to_hex(&response.data))?;
+                if let Request::Control { .. } = self.request {
+                    write!(f, " {}", response.status)?;
+                }
+                Ok(())
+            }
+            Err(errno) => write!(f, "err {}", errno),
+        }
+    }
+}
+
+impl FromStr for Record {
+    type Err = Error;
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let mut tokens = line.split_whitespace();
+        let mut next = || {
+            tokens
+                .next()
+                .ok_or_else(|| invalid_data(format!("Truncated record: {}", line)))
+        };
+        let start = Duration::from_micros(next()?.parse().map_err(invalid_data)?);
+        let elapsed = Duration::from_micros(next()?.parse().map_err(invalid_data)?);
+        let request = match next()? {
+            "bulk_write" => Request::BulkWrite {
+                ep: u8::from_str_radix(next()?, 16).map_err(invalid_data)?,
+                data: from_hex(next()?)?,
+            },
+            "bulk_read" => Request::BulkRead {
+                ep: u8::from_str_radix(next()?, 16).map_err(invalid_data)?,
+                length: next()?.parse().map_err(invalid_data)?,
+            },
+            "control" => {
+                let bytes = from_hex(next()?)?;
+                if bytes.len() != 8 {
+                    return Err(invalid_data(format!("Invalid setup packet: {}", line)));
+                }
+                let mut setup = [0; 8];
+                setup.copy_from_slice(&bytes);
+                Request::Control {
+                    setup,
+                    data: from_hex(next()?)?,
+                }
+            }
+            kind => return Err(invalid_data(format!("Unknown request {}", kind))),
+        };
+        let result = match next()? {
+            "ok" => Ok(Response {
+                length: next()?.parse().map_err(invalid_data)?,
+                data: from_hex(next()?)?,
+                status: match next() {
+                    Ok(status) => status.parse().map_err(invalid_data)?,
+                    Err(_) => 0,
+                },
+            }),
+            "err" => Err(next()?.parse().map_err(invalid_data)?),
+            result => return Err(invalid_data(format!("Unknown result {}", result))),
+        };
+        Ok(Record {
+            start,
+            elapsed,
+            request,
+            result,
+        })
+    }
+}
+
+/// Log all blocking transfers done through the wrapped device.
+///
+/// Example:
+/// ```ignore
+/// let usb = UsbCore::from_device(&device)?;
+/// let mut usb = Recorder::create(usb, "session.rec")?;
+/// usb.bulk_write(1, b"HELLO", 100.into())?;
+/// ```
+pub struct Recorder<U, W: Write> {
+    inner: U,
+    writer: W,
+    started: Instant,
+}
+
+impl<U> Recorder<U, BufWriter<File>> {
+    /// Record to a new file at path
+    pub fn create<P: AsRef<Path>>(inner: U, path: P) -> io::Result<Self> {
+        Ok(Self::new(inner, BufWriter::new(File::create(path)?)))
+    }
+}
+
+impl<U, W: Write> Recorder<U, W> {
+    pub fn new(inner: U, writer: W) -> Self {
+        Self {
+            inner,
+            writer,
+            started: Instant::now(),
+        }
+    }
+
+    /// Access the wrapped device, transfers done this way are not recorded.
+    pub fn inner(&mut self) -> &mut U {
+        &mut self.inner
+    }
+
+    /// Flush the recording and give back the device and writer
+    pub fn into_inner(mut self) -> io::Result<(U, W)> {
+        self.writer.flush()?;
+        Ok((self.inner, self.writer))
+    }
+
+    fn record(
+        &mut self,
+        start: Instant,
+        request: Request,
+        result: Result<Response, i32>,
+    ) -> io::Result<()> {
+        let record = Record {
+            start: start.duration_since(self.started),
+            elapsed: start.elapsed(),
+            request,
+            result,
+        };
+        writeln!(self.writer, "{}", record)?;
+        self.writer.flush()
+    }
+}
+
+impl<U: UsbCoreDriver, W: Write> UsbCoreDriver for Recorder<U, W> {
+    fn new_bulk_in(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
+        self.inner.new_bulk_in(ep, buffer_capacity)
+    }
+
+    fn new_bulk_out(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
+        self.inner.new_bulk_out(ep, buffer_capacity)
+    }
+
+    fn new_control(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> io::Result<ControlTransfer> {
+        self.inner
+            .new_control(request_type, request, value, index, length)
+    }
+}
+
+impl<U: UsbCoreBlocking, W: Write> UsbCoreBlocking for Recorder<U, W> {
+    fn bulk_read(&mut self, ep: u8, mem: &mut [u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        let start = Instant::now();
+        let res = self.inner.bulk_read(ep, mem, timeout);
+        let request = Request::BulkRead {
+            ep: ep | ENDPOINT_IN,
+            length: mem.len(),
+        };
+        let result = match &res {
+            Ok(length) => Ok(Response {
+                length: *length as usize,
+                data: mem[..*length as usize].to_vec(),
+                status: 0,
+            }),
+            Err(e) => Err(errno(e)),
+        };
+        self.record(start, request, result)?;
+        res
+    }
+
+    fn bulk_write(&mut self, ep: u8, mem: &[u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        let start = Instant::now();
+        let res = self.inner.bulk_write(ep, mem, timeout);
+        let request = Request::BulkWrite {
+            ep: ep & 0x7F,
+            data: mem.to_vec(),
+        };
+        let result = match &res {
+            Ok(length) => Ok(Response {
+                length: *length as usize,
+                data: vec![],
+                status: 0,
+            }),
+            Err(e) => Err(errno(e)),
+        };
+        self.record(start, request, result)?;
+        res
+    }
+
+    fn control_async_wait(
+        &mut self,
+        ctrl: ControlTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<ControlTransfer> {
+        let request = Request::Control {
+            setup: ctrl.setup_packet(),
+            data: ctrl.request_data().to_vec(),
+        };
+        let start = Instant::now();
+        let res = self.inner.control_async_wait(ctrl, timeout);
+        let result = match &res {
+            Ok(ctrl) => Ok(Response {
+                length: ctrl.actual_length as usize,
+                data: ctrl.as_slice().to_vec(),
+                status: ctrl.status,
+            }),
+            Err(e) => Err(errno(e)),
+        };
+        self.record(start, request, result)?;
+        res
+    }
+}
+
+/// Serve recorded exchanges back in the same order they were recorded.
+/// Requests must match the recording or they fail with InvalidData and
+/// are stored in divergences().
+///
+/// Example:
+/// ```ignore
+/// let mut usb = Replay::open("session.rec")?;
+/// run_protocol(&mut usb)?;
+/// assert!(usb.divergences().is_empty() && usb.remaining() == 0);
+/// ```
+pub struct Replay {
+    records: Vec<Record>,
+    position: usize,
+    divergences: Vec<Divergence>,
+    realtime: bool,
+}
+
+impl Replay {
+    pub fn new(records: Vec<Record>) -> Self {
+        Self {
+            records,
+            position: 0,
+            divergences: vec![],
+            realtime: false,
+        }
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut records = vec![];
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            records.push(line.parse()?);
+        }
+        Ok(Self::new(records))
+    }
+
+    /// Sleep as long as the recorded request took before answering
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    pub fn records(&self) -> &[Record] {
+        &self.records
+    }
+
+    pub fn divergences(&self) -> &[Divergence] {
+        &self.divergences
+    }
+
+    /// Number of records not yet replayed
+    pub fn remaining(&self) -> usize {
+        self.records.len() - self.position
+    }
+
+    /// Take the next record if it matches the request
+    fn next_record(&mut self, got: Request) -> io::Result<Result<Response, i32>> {
+        let expected = self.records.get(self.position);
+        match expected {
+            Some(record) if record.request == got => {
+                self.position += 1;
+                if self.realtime {
+                    std::thread::sleep(record.elapsed);
+                }
+                Ok(record.result.clone())
+            }
+            _ => {
+                let divergence = Divergence {
+                    index: self.position,
+                    expected: expected.map(|r| r.request.clone()),
+                    got,
+                };
+                let msg = match &divergence.expected {
+                    Some(expected) => format!(
+                        "Replay diverged at record {}: expected {} got {}",
+                        divergence.index, expected, divergence.got
+                    ),
+                    None => format!("Replay diverged after last record: got {}", divergence.got),
+                };
+                log::error!("{}", msg);
+                self.divergences.push(divergence);
+                Err(invalid_data(msg))
+            }
+        }
+    }
+}
+
+impl UsbCoreDriver for Replay {
+    fn new_bulk_in(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
+        Ok(BulkTransfer::input(
+            ep,
+            TransferBuffer::heap(buffer_capacity)?,
+        ))
+    }
+
+    fn new_bulk_out(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
+        Ok(BulkTransfer::output(
+            ep,
+            TransferBuffer::heap(buffer_capacity)?,
+        ))
+    }
+
+    fn new_control(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> io::Result<ControlTransfer> {
+        let buffer = TransferBuffer::heap(length as usize + 8)?;
+        ControlTransfer::with_setup(buffer, request_type, request, value, index, length)
+    }
+}
+
+impl UsbCoreBlocking for Replay {
+    fn bulk_read(&mut self, ep: u8, mem: &mut [u8], _timeout: TimeoutMillis) -> io::Result<u32> {
+        let request = Request::BulkRead {
+            ep: ep | ENDPOINT_IN,
+            length: mem.len(),
+        };
+        match self.next_record(request)? {
+            Ok(response) => {
+                let length = response.data.len().min(mem.len());
+                mem[..length].copy_from_slice(&response.data[..length]);
+                Ok(length as u32)
+            }
+            Err(errno) => Err(Error::from_raw_os_error(errno)),
+        }
+    }
+
+    fn bulk_write(&mut self, ep: u8, mem: &[u8], _timeout: TimeoutMillis) -> io::Result<u32> {
+        let request = Request::BulkWrite {
+            ep: ep & 0x7F,
+            data: mem.to_vec(),
+        };
+        match self.next_record(request)? {
+            Ok(response) => Ok(response.length as u32),
+            Err(errno) => Err(Error::from_raw_os_error(errno)),
+        }
+    }
+
+    fn control_async_wait(
+        &mut self,
+        mut ctrl: ControlTransfer,
+        _timeout: TimeoutMillis,
+    ) -> io::Result<ControlTransfer> {
+        let request = Request::Control {
+            setup: ctrl.setup_packet(),
+            data: ctrl.request_data().to_vec(),
+        };
+        match self.next_record(request)? {
+            Ok(response) => {
+                ctrl.set_response(&response.data, response.length as u16);
+                ctrl.status = response.status;
+                Ok(ctrl)
+            }
+            Err(errno) => Err(Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_control_round_trip() {
+        let mut replay = Replay::new(vec![]);
+        let ctrl = replay.new_control_in(0xC0, 0x01, 0, 0, 4).unwrap();
+        let line = format!(
+            "10 20 control {} - ok 0 - {}",
+            to_hex(&ctrl.setup_packet()),
+            -libc::EPIPE
+        );
+        let record: Record = line.parse().unwrap();
+        assert_eq!(record.to_string(), line);
+
+        let mut usb = Recorder::new(Replay::new(vec![record]), vec![]);
+        let ctrl = usb
+            .control_async_wait(ctrl, TimeoutMillis::from(100))
+            .unwrap();
+        assert_eq!(ctrl.status, -libc::EPIPE);
+
+        let (_, written) = usb.into_inner().unwrap();
+        let replay = Replay::from_reader(&written[..]).unwrap();
+        match &replay.records()[0].result {
+            Ok(response) => assert_eq!(response.status, -libc::EPIPE),
+            Err(errno) => panic!("Recorded as error {}", errno),
+        }
+    }
+
+    #[test]
+    fn control_without_status_is_ok() {
+        let record: Record = "0 0 control c001000000000400 - ok 2 abcd".parse().unwrap();
+        assert_eq!(record.result.unwrap().status, 0);
+    }
+}
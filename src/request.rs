@@ -0,0 +1,217 @@
+//! Chapter 9 standard requests and the bmRequestType field of the setup packet.
+use crate::endpoint::{ENDPOINT_IN, ENDPOINT_OUT};
+use std::fmt;
+
+pub const REQUEST_GET_STATUS: u8 = 0x00;
+pub const REQUEST_CLEAR_FEATURE: u8 = 0x01;
+pub const REQUEST_SET_FEATURE: u8 = 0x03;
+pub const REQUEST_SET_ADDRESS: u8 = 0x05;
+pub const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+pub const REQUEST_SET_DESCRIPTOR: u8 = 0x07;
+pub const REQUEST_GET_CONFIGURATION: u8 = 0x08;
+pub const REQUEST_SET_CONFIGURATION: u8 = 0x09;
+pub const REQUEST_GET_INTERFACE: u8 = 0x0A;
+pub const REQUEST_SET_INTERFACE: u8 = 0x0B;
+pub const REQUEST_SYNCH_FRAME: u8 = 0x0C;
+pub const REQUEST_SET_SEL: u8 = 0x30;
+pub const REQUEST_SET_ISOCH_DELAY: u8 = 0x31;
+
+/// Feature selectors used with SET_FEATURE and CLEAR_FEATURE
+pub const FEATURE_ENDPOINT_HALT: u16 = 0;
+pub const FEATURE_FUNCTION_SUSPEND: u16 = 0;
+pub const FEATURE_DEVICE_REMOTE_WAKEUP: u16 = 1;
+pub const FEATURE_TEST_MODE: u16 = 2;
+pub const FEATURE_U1_ENABLE: u16 = 48;
+pub const FEATURE_U2_ENABLE: u16 = 49;
+pub const FEATURE_LTM_ENABLE: u16 = 50;
+
+/// Bits returned by GET_STATUS to the device
+pub const STATUS_SELF_POWERED: u16 = 1 << 0;
+pub const STATUS_REMOTE_WAKEUP: u16 = 1 << 1;
+pub const STATUS_U1_ENABLE: u16 = 1 << 2;
+pub const STATUS_U2_ENABLE: u16 = 1 << 3;
+pub const STATUS_LTM_ENABLE: u16 = 1 << 4;
+/// Bit returned by GET_STATUS to an endpoint
+pub const STATUS_ENDPOINT_HALT: u16 = 1 << 0;
+
+/// Descriptor types used with GET_DESCRIPTOR
+pub const DESCRIPTOR_DEVICE: u8 = 0x01;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 0x02;
+pub const DESCRIPTOR_STRING: u8 = 0x03;
+pub const DESCRIPTOR_INTERFACE: u8 = 0x04;
+pub const DESCRIPTOR_ENDPOINT: u8 = 0x05;
+pub const DESCRIPTOR_BOS: u8 = 0x0F;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Out,
+    In,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Standard,
+    Class,
+    Vendor,
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recipient {
+    Device,
+    Interface,
+    Endpoint,
+    Other,
+}
+
+/// bmRequestType builder
+/// Example:
+/// ```
+/// use usbapi::request::RequestType;
+/// let request_type = RequestType::new().input().vendor().interface();
+/// assert_eq!(u8::from(request_type), 0xC1);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RequestType(u8);
+
+impl RequestType {
+    /// Standard request to the device host to device
+    pub const fn new() -> Self {
+        Self(0)
+    }
+
+    pub const fn input(self) -> Self {
+        Self(self.0 | ENDPOINT_IN)
+    }
+
+    pub const fn output(self) -> Self {
+        Self((self.0 & !ENDPOINT_IN) | ENDPOINT_OUT)
+    }
+
+    pub const fn standard(self) -> Self {
+        Self(self.0 & !0x60)
+    }
+
+    pub const fn class(self) -> Self {
+        Self((self.0 & !0x60) | 0x01 << 5)
+    }
+
+    pub const fn vendor(self) -> Self {
+        Self((self.0 & !0x60) | 0x02 << 5)
+    }
+
+    pub const fn device(self) -> Self {
+        Self(self.0 & !0x1F)
+    }
+
+    pub const fn interface(self) -> Self {
+        Self((self.0 & !0x1F) | 0x01)
+    }
+
+    pub const fn endpoint(self) -> Self {
+        Self((self.0 & !0x1F) | 0x02)
+    }
+
+    pub const fn other(self) -> Self {
+        Self((self.0 & !0x1F) | 0x03)
+    }
+
+    pub const fn with_direction(self, direction: Direction) -> Self {
+        match direction {
+            Direction::In => self.input(),
+            Direction::Out => self.output(),
+        }
+    }
+
+    pub const fn with_recipient(self, recipient: Recipient) -> Self {
+        match recipient {
+            Recipient::Device => self.device(),
+            Recipient::Interface => self.interface(),
+            Recipient::Endpoint => self.endpoint(),
+            Recipient::Other => self.other(),
+        }
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn direction(self) -> Direction {
+        if self.0 & ENDPOINT_IN == ENDPOINT_IN {
+            Direction::In
+        } else {
+            Direction::Out
+        }
+    }
+
+    pub fn kind(self) -> Kind {
+        match (self.0 >> 5) & 0x03 {
+            0 => Kind::Standard,
+            1 => Kind::Class,
+            2 => Kind::Vendor,
+            _ => Kind::Reserved,
+        }
+    }
+
+    /// Reserved recipients 4..31 are reported as Other
+    pub fn recipient(self) -> Recipient {
+        match self.0 & 0x1F {
+            0 => Recipient::Device,
+            1 => Recipient::Interface,
+            2 => Recipient::Endpoint,
+            _ => Recipient::Other,
+        }
+    }
+}
+
+impl From<RequestType> for u8 {
+    fn from(request_type: RequestType) -> u8 {
+        request_type.0
+    }
+}
+
+impl From<u8> for RequestType {
+    fn from(bits: u8) -> Self {
+        Self(bits)
+    }
+}
+
+impl fmt::Display for RequestType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "0x{:02X} ({:?} {:?} {:?})",
+            self.0,
+            self.direction(),
+            self.kind(),
+            self.recipient()
+        )
+    }
+}
+
+/// Setup packet of a blocking control transfer, wLength is the size of
+/// the data buffer.
+/// Example:
+/// ```
+/// use usbapi::request::{ControlRequest, RequestType, REQUEST_GET_STATUS};
+/// let req = ControlRequest::new(RequestType::new().input(), REQUEST_GET_STATUS, 0, 0);
+/// assert_eq!(req.request, REQUEST_GET_STATUS);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControlRequest {
+    pub request_type: RequestType,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+impl ControlRequest {
+    pub const fn new(request_type: RequestType, request: u8, value: u16, index: u16) -> Self {
+        Self {
+            request_type,
+            request,
+            value,
+            index,
+        }
+    }
+}
@@ -1,20 +1,226 @@
 use crate::endpoint::*;
+use crate::TimeoutMillis;
+use std::any::Any;
 use std::fmt;
 use std::io;
 use std::io::Write;
 use std::io::{Error, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::time::{Duration, Instant};
 
-pub(crate) type Deallocate = Box<dyn Fn(*mut u8, usize) + 'static>;
+pub(crate) type Deallocate = Box<dyn Fn(*mut u8, usize) + Send + Sync + 'static>;
+
+/// Memory owned by a transfer, mmap'd from usbfs or allocated on the heap.
+/// Freed when the transfer is dropped.
+pub struct TransferBuffer {
+    ptr: *mut u8,
+    capacity: usize,
+    deallocate: Deallocate,
+}
+
+// The memory is owned by the buffer and only reached through &self or &mut self
+unsafe impl Send for TransferBuffer {}
+unsafe impl Sync for TransferBuffer {}
+
+impl TransferBuffer {
+    /// # Safety
+    /// ptr must be valid and initialized for capacity bytes until deallocate is called
+    pub(crate) unsafe fn from_raw(ptr: *mut u8, capacity: usize, deallocate: Deallocate) -> Self {
+        Self {
+            ptr,
+            capacity,
+            deallocate,
+        }
+    }
+
+    /// Zero filled heap buffer, used when mmap is not available
+    pub fn heap(capacity: usize) -> io::Result<Self> {
+        let ptr = unsafe { libc::calloc(capacity.max(1), 1) };
+        if ptr.is_null() {
+            return Err(io::Error::from_raw_os_error(libc::ENOMEM));
+        }
+        Ok(unsafe { Self::from_raw(ptr as *mut u8, capacity, Box::new(free)) })
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Address of the memory, stays the same while the transfer is alive
+    pub(crate) fn address(&self) -> usize {
+        self.ptr as usize
+    }
+
+    /// Pointer handed to the kernel when the transfer is submitted
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.ptr
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.capacity) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr, self.capacity) }
+    }
+}
+
+impl Drop for TransferBuffer {
+    fn drop(&mut self) {
+        if !self.ptr.is_null() {
+            (self.deallocate)(self.ptr, self.capacity);
+        }
+    }
+}
+
+impl Deref for TransferBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl DerefMut for TransferBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
+}
+
+impl fmt::Debug for TransferBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TransferBuffer({:X?}, {})", self.ptr, self.capacity)
+    }
+}
+
+fn free(mem: *mut u8, _size: usize) {
+    unsafe {
+        libc::free(mem as *mut libc::c_void);
+    }
+}
+
+/// Copy as much of inbuf as fits after length, returns bytes copied
+fn append(buffer: &mut [u8], length: usize, inbuf: &[u8]) -> usize {
+    let free = &mut buffer[length..];
+    let n = free.len().min(inbuf.len());
+    free[..n].copy_from_slice(&inbuf[..n]);
+    n
+}
+
+/// buffer_length of a control transfer, setup included, is a u16
+fn control_too_long() -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        "Control transfer is longer than 65535 bytes",
+    )
+}
+
+/// User data and timing that follow a transfer through submit and reap.
+/// Timestamps and URB fields are filled in by UsbCore async transfers.
+/// Example:
+/// ```ignore
+/// bulk.info_mut().set_user_data(request_id);
+/// usb.submit_bulk(bulk)?;
+/// ...
+/// if let TransferKind::Bulk(bulk) = usb.async_response()? {
+///     let request_id = bulk.info().user_data::<u64>();
+///     println!("{:?} took {:?}", request_id, bulk.info().latency());
+/// }
+/// ```
+#[derive(Default)]
+pub struct TransferInfo {
+    user_data: Option<Box<dyn Any + Send + Sync>>,
+    submitted: Option<Instant>,
+    completed: Option<Instant>,
+    error_count: i32,
+    start_frame: i32,
+}
+
+impl fmt::Debug for TransferInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TransferInfo")
+            .field("user_data", &self.user_data.is_some())
+            .field("submitted", &self.submitted)
+            .field("completed", &self.completed)
+            .field("error_count", &self.error_count)
+            .field("start_frame", &self.start_frame)
+            .finish()
+    }
+}
+
+impl TransferInfo {
+    /// Attach any value to the transfer, replaces the previous one
+    pub fn set_user_data<T: Any + Send + Sync>(&mut self, data: T) {
+        self.user_data = Some(Box::new(data));
+    }
+
+    /// None if nothing is attached or it is not a T
+    pub fn user_data<T: Any>(&self) -> Option<&T> {
+        self.user_data.as_ref()?.downcast_ref()
+    }
+
+    pub fn user_data_mut<T: Any>(&mut self) -> Option<&mut T> {
+        self.user_data.as_mut()?.downcast_mut()
+    }
+
+    /// Detach the value if it is a T
+    pub fn take_user_data<T: Any>(&mut self) -> Option<T> {
+        match self.user_data.take()?.downcast() {
+            Ok(data) => Some(*data),
+            Err(data) => {
+                self.user_data = Some(data);
+                None
+            }
+        }
+    }
+
+    /// When the transfer was last submitted
+    pub fn submitted(&self) -> Option<Instant> {
+        self.submitted
+    }
+
+    /// When the transfer was reaped after the last submit
+    pub fn completed(&self) -> Option<Instant> {
+        self.completed
+    }
+
+    /// Time from submit to reap
+    pub fn latency(&self) -> Option<Duration> {
+        Some(self.completed?.saturating_duration_since(self.submitted?))
+    }
+
+    /// Number of isochronous packets with errors from the URB
+    pub fn error_count(&self) -> i32 {
+        self.error_count
+    }
+
+    /// Frame the URB was scheduled in, for isochronous transfers
+    pub fn start_frame(&self) -> i32 {
+        self.start_frame
+    }
+
+    pub(crate) fn submit(&mut self) {
+        self.submitted = Some(Instant::now());
+        self.completed = None;
+        self.error_count = 0;
+        self.start_frame = 0;
+    }
+
+    pub(crate) fn complete(&mut self, error_count: i32, start_frame: i32) {
+        self.completed = Some(Instant::now());
+        self.error_count = error_count;
+        self.start_frame = start_frame;
+    }
+}
 
 pub struct ControlTransfer {
-    pub buffer: *mut u8,
+    // 8 byte setup packet followed by data
+    buffer: TransferBuffer,
     // buffer length is the value sent to kernel
     pub(crate) buffer_length: u16,
-    // capacity of the buffer needed when call deallocator
-    pub(crate) buffer_capacity: u16,
     // given back from kernel
     pub(crate) actual_length: u16,
-    deallocate: Deallocate,
+    pub status: i32,
+    info: TransferInfo,
 }
 
 impl fmt::Display for ControlTransfer {
@@ -22,54 +228,141 @@ impl fmt::Display for ControlTransfer {
         write!(
             f,
             "|Control|{}|{}|{}|",
-            self.buffer_length, self.actual_length, self.buffer_capacity
+            self.buffer_length,
+            self.actual_length,
+            self.buffer.capacity()
         )
     }
 }
 
 impl ControlTransfer {
-    pub(crate) fn new<DEALOC>(
-        buffer: *mut u8,
-        buffer_capacity: u16,
-        buffer_length: u16,
-        deallocate: DEALOC,
-    ) -> Self
-    where
-        DEALOC: Fn(*mut u8, usize) + 'static,
-    {
+    pub(crate) fn new(buffer: TransferBuffer, buffer_length: u16) -> Self {
         Self {
             buffer,
             buffer_length,
-            buffer_capacity,
             actual_length: 0,
-            deallocate: Box::new(deallocate),
+            status: 0,
+            info: TransferInfo::default(),
         }
     }
-}
 
-impl Drop for ControlTransfer {
-    fn drop(&mut self) {
-        if !self.buffer.is_null() {
-            (self.deallocate)(self.buffer, self.buffer_capacity as usize);
+    /// Create a control transfer and write the setup packet to the buffer
+    /// buffer must have room for 8 + length bytes.
+    pub(crate) fn with_setup(
+        buffer: TransferBuffer,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> io::Result<Self> {
+        if buffer.capacity() < length as usize + 8 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Control buffer too small for setup and data",
+            ));
         }
+        let mut ctrl = ControlTransfer::new(buffer, 0);
+        ctrl.write_all(&[
+            request_type,
+            request,
+            (value & 0x00FF) as u8,
+            (value >> 8) as u8,
+            (index & 0x00FF) as u8,
+            (index >> 8) as u8,
+            (length & 0x00FF) as u8,
+            (length >> 8) as u8,
+        ])?;
+        Ok(ctrl)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// Data after the setup packet, received data for control in
+    pub fn as_slice(&self) -> &[u8] {
+        let end = 8 + self.actual_length as usize;
+        assert!(end <= self.buffer_length.max(8) as usize);
+        &self.buffer[8..end]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let end = 8 + self.actual_length as usize;
+        assert!(end <= self.buffer_length.max(8) as usize);
+        &mut self.buffer[8..end]
+    }
+
+    pub fn info(&self) -> &TransferInfo {
+        &self.info
+    }
+
+    pub fn info_mut(&mut self) -> &mut TransferInfo {
+        &mut self.info
+    }
+
+    pub(crate) fn buffer_address(&self) -> usize {
+        self.buffer.address()
+    }
+
+    pub(crate) fn buffer_mut_ptr(&mut self) -> *mut u8 {
+        self.buffer.as_mut_ptr()
+    }
+
+    /// The 8 byte setup packet in front of the data
+    pub(crate) fn setup_packet(&self) -> [u8; 8] {
+        let mut setup = [0; 8];
+        if self.buffer_length >= 8 {
+            setup.copy_from_slice(&self.buffer[..8]);
+        }
+        setup
+    }
+
+    /// Store data as if it was received from the device (control in)
+    /// For control out only actual_length is updated.
+    pub(crate) fn set_response(&mut self, data: &[u8], actual_length: u16) {
+        let length = (actual_length as usize).min(self.buffer_length as usize - 8);
+        let length = if self.setup_packet()[0] & ENDPOINT_IN == ENDPOINT_IN {
+            let length = length.min(data.len());
+            self.buffer[8..8 + length].copy_from_slice(&data[..length]);
+            length
+        } else {
+            length
+        };
+        self.actual_length = length as u16;
+    }
+
+    /// Data written after the setup packet (control out)
+    pub(crate) fn request_data(&self) -> &[u8] {
+        if self.buffer_length <= 8 || self.setup_packet()[0] & ENDPOINT_IN == ENDPOINT_IN {
+            return &[];
+        }
+        &self.buffer[8..self.buffer_length as usize]
+    }
+}
+
+impl Deref for ControlTransfer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl DerefMut for ControlTransfer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
     }
 }
 
 impl Write for ControlTransfer {
     fn write(&mut self, inbuf: &[u8]) -> io::Result<usize> {
-        let buf = unsafe {
-            let buf = self.buffer;
-            std::slice::from_raw_parts_mut(
-                buf.offset(self.buffer_length as isize),
-                self.buffer_capacity as usize - self.buffer_length as usize,
-            )
-        };
-
-        for (i, byte) in inbuf.iter().enumerate() {
-            buf[i] = *byte;
-            self.buffer_length += 1;
+        let n = append(&mut self.buffer, self.buffer_length as usize, inbuf);
+        let length = self.buffer_length as usize + n;
+        if length > u16::MAX as usize {
+            return Err(control_too_long());
         }
-        Ok(inbuf.len())
+        self.buffer_length = length as u16;
+        Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -79,16 +372,16 @@ impl Write for ControlTransfer {
 }
 
 pub struct BulkTransfer {
-    pub(crate) buffer: *mut u8,
+    buffer: TransferBuffer,
     // Lower layer write or read length
     pub buffer_length: usize,
     pub actual_length: usize,
     pub status: i32,
-    // allocedata size
-    pub buffer_capacity: usize,
     pub endpoint: Endpoint,
-    // give back allocated memory
-    deallocate: Deallocate,
+    zero_packet: bool,
+    short_not_ok: bool,
+    no_interrupt: bool,
+    info: TransferInfo,
 }
 
 impl fmt::Display for BulkTransfer {
@@ -96,63 +389,128 @@ impl fmt::Display for BulkTransfer {
         write!(
             f,
             "|{}|{}|{}|{}|",
-            self.endpoint, self.buffer_length, self.actual_length, self.buffer_capacity
+            self.endpoint,
+            self.buffer_length,
+            self.actual_length,
+            self.buffer.capacity()
         )
     }
 }
 
 impl BulkTransfer {
-    /// Create new bulk input (Read)
-    /// the deallocate is called when BulkTransfer goes out of scope to cleanup
-    /// allocated memory
-    pub fn input<DEALOC>(
-        ep: u8,
-        buffer: *mut u8,
-        buffer_capacity: usize,
-        deallocate: DEALOC,
-    ) -> Self
-    where
-        DEALOC: Fn(*mut u8, usize) + 'static,
-    {
+    /// Create new bulk input (Read) reading up to the buffer capacity
+    pub fn input(ep: u8, buffer: TransferBuffer) -> Self {
         Self {
+            buffer_length: buffer.capacity(),
             buffer,
-            buffer_capacity,
-            buffer_length: buffer_capacity,
             actual_length: 0,
             status: 0,
             endpoint: Endpoint::bulk_in(ep),
-            deallocate: Box::new(deallocate),
+            zero_packet: false,
+            short_not_ok: false,
+            no_interrupt: false,
+            info: TransferInfo::default(),
         }
     }
 
     /// Create a write
-    pub fn output<DEALOC>(
-        ep: u8,
-        buffer: *mut u8,
-        buffer_capacity: usize,
-        deallocate: DEALOC,
-    ) -> Self
-    where
-        DEALOC: Fn(*mut u8, usize) + 'static,
-    {
+    pub fn output(ep: u8, buffer: TransferBuffer) -> Self {
         Self {
             buffer,
-            buffer_capacity,
             buffer_length: 0, // incremented when we put data in the buffer
             actual_length: 0,
             status: 0,
             endpoint: Endpoint::bulk_out(ep),
-            deallocate: Box::new(deallocate),
+            zero_packet: false,
+            short_not_ok: false,
+            no_interrupt: false,
+            info: TransferInfo::default(),
         }
     }
-}
 
-impl Drop for BulkTransfer {
-    fn drop(&mut self) {
-        if !self.buffer.is_null() {
-            (self.deallocate)(self.buffer, self.buffer_capacity);
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity()
+    }
+
+    /// OUT only, end a transfer that is a multiple of wMaxPacketSize
+    /// with a zero length packet so the device sees where it ends.
+    pub fn set_zero_packet(&mut self, enable: bool) {
+        self.zero_packet = enable;
+    }
+
+    pub fn zero_packet(&self) -> bool {
+        self.zero_packet
+    }
+
+    /// IN only, complete with status -EREMOTEIO if the device sends
+    /// less than buffer_length.
+    pub fn set_short_not_ok(&mut self, enable: bool) {
+        self.short_not_ok = enable;
+    }
+
+    pub fn short_not_ok(&self) -> bool {
+        self.short_not_ok
+    }
+
+    /// Hint that no interrupt is needed on completion, use on all but
+    /// the last transfer of a batch.
+    pub fn set_no_interrupt(&mut self, enable: bool) {
+        self.no_interrupt = enable;
+    }
+
+    pub fn no_interrupt(&self) -> bool {
+        self.no_interrupt
+    }
+
+    /// Received data for bulk in, data to send for bulk out
+    pub fn as_slice(&self) -> &[u8] {
+        let length = self.data_length();
+        &self.buffer[..length]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let length = self.data_length();
+        &mut self.buffer[..length]
+    }
+
+    fn data_length(&self) -> usize {
+        assert!(self.actual_length <= self.buffer_length);
+        assert!(self.buffer_length <= self.buffer.capacity());
+        if self.endpoint.is_bulk_in() {
+            self.actual_length
+        } else {
+            self.buffer_length
         }
     }
+
+    pub fn info(&self) -> &TransferInfo {
+        &self.info
+    }
+
+    pub fn info_mut(&mut self) -> &mut TransferInfo {
+        &mut self.info
+    }
+
+    pub(crate) fn buffer_address(&self) -> usize {
+        self.buffer.address()
+    }
+
+    pub(crate) fn buffer_mut_ptr(&mut self) -> *mut u8 {
+        self.buffer.as_mut_ptr()
+    }
+}
+
+impl Deref for BulkTransfer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl DerefMut for BulkTransfer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.as_mut_slice()
+    }
 }
 
 impl Write for BulkTransfer {
@@ -163,21 +521,9 @@ impl Write for BulkTransfer {
                 "Can not write to an bulk in endpoint.",
             ));
         }
-        let buf = unsafe {
-            let buf = self.buffer;
-            std::slice::from_raw_parts_mut(
-                buf.add(self.buffer_length),
-                self.buffer_capacity - self.buffer_length,
-            )
-        };
-        assert!(!buf.is_empty());
-
-        for (i, byte) in inbuf.iter().enumerate() {
-            buf[i] = *byte;
-            self.buffer_length += 1;
-        }
-
-        Ok(inbuf.len())
+        let n = append(&mut self.buffer, self.buffer_length, inbuf);
+        self.buffer_length += n;
+        Ok(n)
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -205,8 +551,23 @@ impl fmt::Display for TransferKind {
     }
 }
 
+#[deprecated(note = "use as_slice() or Deref on the transfer")]
 pub trait BufferSlice {
-    fn buffer_from_raw<'a>(&self) -> &'a [u8];
+    fn buffer_from_raw(&self) -> &[u8];
+}
+
+#[allow(deprecated)]
+impl BufferSlice for BulkTransfer {
+    fn buffer_from_raw(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+#[allow(deprecated)]
+impl BufferSlice for ControlTransfer {
+    fn buffer_from_raw(&self) -> &[u8] {
+        self.as_slice()
+    }
 }
 
 pub trait UsbCoreDriver {
@@ -231,6 +592,9 @@ pub trait UsbCoreDriver {
         index: u16,
         buffer: &[u8],
     ) -> io::Result<ControlTransfer> {
+        if buffer.len() > u16::MAX as usize {
+            return Err(control_too_long());
+        }
         let mut ctrl = self.new_control(
             request_type | ENDPOINT_OUT,
             request,
@@ -252,7 +616,10 @@ pub trait UsbCoreDriver {
     ) -> io::Result<ControlTransfer> {
         let mut ctrl =
             self.new_control(request_type | ENDPOINT_IN, request, value, index, length)?;
-        ctrl.buffer_length += length;
+        ctrl.buffer_length = ctrl
+            .buffer_length
+            .checked_add(length)
+            .ok_or_else(control_too_long)?;
         Ok(ctrl)
     }
 
@@ -267,3 +634,32 @@ pub trait UsbCoreDriver {
         self.new_control(request_type, request, value, index, 0)
     }
 }
+
+/// Blocking transfers, implemented by UsbCore and by the record/replay
+/// wrappers so protocol code can be run without a device attached.
+pub trait UsbCoreBlocking {
+    fn bulk_read(&mut self, ep: u8, mem: &mut [u8], timeout: TimeoutMillis) -> io::Result<u32>;
+    fn bulk_write(&mut self, ep: u8, mem: &[u8], timeout: TimeoutMillis) -> io::Result<u32>;
+    fn control_async_wait(
+        &mut self,
+        ctrl: ControlTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<ControlTransfer>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::Replay;
+
+    #[test]
+    fn control_longer_than_u16_is_rejected() {
+        let mut usb = Replay::new(vec![]);
+        let res = usb.new_control_in(0xC0, 1, 0, 0, u16::MAX);
+        assert_eq!(res.err().map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+        let res = usb.new_control_out(0x40, 1, 0, 0, &vec![0; u16::MAX as usize]);
+        assert_eq!(res.err().map(|e| e.kind()), Some(ErrorKind::InvalidInput));
+        let ctrl = usb.new_control_in(0xC0, 1, 0, 0, u16::MAX - 8).unwrap();
+        assert_eq!(ctrl.buffer_length, u16::MAX);
+    }
+}
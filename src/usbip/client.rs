@@ -0,0 +1,479 @@
+use super::protocol::*;
+use crate::descriptors::descriptor::string_from_descriptor;
+use crate::usb_transfer::{BulkTransfer, ControlTransfer, TransferBuffer};
+use crate::{TimeoutMillis, UsbCoreBlocking, UsbCoreDriver, UsbDevice, ENDPOINT_IN};
+use nix::poll::{poll, PollFd, PollFlags};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::{Error, ErrorKind, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+// Most devices taken from OP_REP_DEVLIST, 127 per bus on 32 buses
+const MAX_DEVICES: u32 = 127 * 32;
+// How long unlink() waits for RET_UNLINK
+const UNLINK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Drive a device exported by a usbipd server without the vhci driver.
+///
+/// Example:
+/// ```ignore
+/// for dev in UsbIpClient::list_devices("localhost:3240")? {
+///     println!("{} {:04x}:{:04x}", dev.busid, dev.id_vendor, dev.id_product);
+/// }
+/// let mut usb = UsbIpClient::import("localhost:3240", "1-2")?;
+/// usb.bulk_write(1, b"HELLO", 100.into())?;
+/// ```
+pub struct UsbIpClient {
+    stream: TcpStream,
+    device: UsbIpDevice,
+    seqnum: u32,
+    // direction of submitted URBs, RET_SUBMIT does not tell
+    pending: HashMap<u32, bool>,
+    // replies received while waiting for another seqnum
+    completed: HashMap<u32, RetSubmit>,
+    unlinked: HashMap<u32, RetUnlink>,
+    // seqnum and direction of URBs being unlinked by unlink seqnum,
+    // their RET_SUBMIT can still arrive until RET_UNLINK is read
+    unlinking: HashMap<u32, (u32, bool)>,
+    // unlink seqnums nobody waits for anymore
+    abandoned: HashSet<u32>,
+}
+
+impl UsbIpClient {
+    /// Ask the server for exported devices (OP_REQ_DEVLIST)
+    pub fn list_devices<A: ToSocketAddrs>(addr: A) -> io::Result<Vec<UsbIpDevice>> {
+        let mut stream = TcpStream::connect(addr)?;
+        OpHeader::new(OP_REQ_DEVLIST, 0).write(&mut stream)?;
+        OpHeader::read(&mut stream)?.expect(OP_REP_DEVLIST)?;
+        let n = read_u32(&mut stream)?;
+        if n > MAX_DEVICES {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Server reports {} devices", n),
+            ));
+        }
+        let mut devices = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            devices.push(UsbIpDevice::read(&mut stream, true)?);
+        }
+        Ok(devices)
+    }
+
+    /// Import the device with busid (OP_REQ_IMPORT) after which URBs
+    /// can be submitted over the same connection.
+    pub fn import<A: ToSocketAddrs>(addr: A, busid: &str) -> io::Result<Self> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        let mut req = Vec::with_capacity(40);
+        OpHeader::new(OP_REQ_IMPORT, 0).write(&mut req)?;
+        let mut id = [0; 32];
+        let length = busid.len().min(id.len() - 1);
+        id[..length].copy_from_slice(&busid.as_bytes()[..length]);
+        req.extend_from_slice(&id);
+        stream.write_all(&req)?;
+        OpHeader::read(&mut stream)
+            .and_then(|h| h.expect(OP_REP_IMPORT))
+            .map_err(|e| Error::new(e.kind(), format!("Import of {} failed: {}", busid, e)))?;
+        let device = UsbIpDevice::read(&mut stream, false)?;
+        Ok(Self {
+            stream,
+            device,
+            seqnum: 0,
+            pending: HashMap::new(),
+            completed: HashMap::new(),
+            unlinked: HashMap::new(),
+            unlinking: HashMap::new(),
+            abandoned: HashSet::new(),
+        })
+    }
+
+    /// The imported device as reported by the server
+    pub fn device(&self) -> &UsbIpDevice {
+        &self.device
+    }
+
+    fn next_seqnum(&mut self) -> u32 {
+        self.seqnum = self.seqnum.wrapping_add(1).max(1);
+        self.seqnum
+    }
+
+    /// Send USBIP_CMD_SUBMIT without waiting for the result.
+    /// ep includes the direction bit, for control the direction is taken
+    /// from the setup packet. length is the IN buffer size.
+    /// Returns the seqnum to pass to wait()
+    pub fn submit(
+        &mut self,
+        ep: u8,
+        setup: [u8; 8],
+        data: &[u8],
+        length: usize,
+    ) -> io::Result<u32> {
+        let is_in = if ep & 0x7F == 0 {
+            setup[0] & ENDPOINT_IN == ENDPOINT_IN
+        } else {
+            ep & ENDPOINT_IN == ENDPOINT_IN
+        };
+        let seqnum = self.next_seqnum();
+        let cmd = CmdSubmit {
+            header: UsbIpHeader {
+                command: USBIP_CMD_SUBMIT,
+                seqnum,
+                devid: self.device.devid(),
+                direction: if is_in { USBIP_DIR_IN } else { USBIP_DIR_OUT },
+                ep: (ep & 0x7F) as u32,
+            },
+            transfer_buffer_length: if is_in { length } else { data.len() } as i32,
+            setup,
+            data: if is_in { vec![] } else { data.to_vec() },
+            ..Default::default()
+        };
+        cmd.write(&mut self.stream)?;
+        self.pending.insert(seqnum, is_in);
+        Ok(seqnum)
+    }
+
+    /// Wait until readable, returns false on timeout
+    fn poll(&self, deadline: Option<Instant>) -> io::Result<bool> {
+        let timeout = match deadline {
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                // Round up so we do not spin on sub millisecond leftovers
+                ((left.as_micros() + 999) / 1000).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+        let mut fds = [PollFd::new(self.stream.as_raw_fd(), PollFlags::POLLIN)];
+        let n = poll(&mut fds, timeout).map_err(|_| io::Error::last_os_error())?;
+        Ok(n > 0)
+    }
+
+    /// Read one reply and store it
+    fn read_reply(&mut self) -> io::Result<()> {
+        let header = UsbIpHeader::read(&mut self.stream)?;
+        match header.command {
+            USBIP_RET_SUBMIT => {
+                let seqnum = header.seqnum;
+                let is_in = self
+                    .pending
+                    .remove(&seqnum)
+                    .or_else(|| {
+                        self.unlinking
+                            .values()
+                            .find(|(urb, _)| *urb == seqnum)
+                            .map(|(_, is_in)| *is_in)
+                    })
+                    .ok_or_else(|| {
+                        Error::new(
+                            ErrorKind::InvalidData,
+                            format!("RET_SUBMIT for unknown seqnum {}", seqnum),
+                        )
+                    })?;
+                let ret = RetSubmit::read_body(header, &mut self.stream, is_in)?;
+                self.completed.insert(seqnum, ret);
+            }
+            USBIP_RET_UNLINK => {
+                let ret = RetUnlink::read_body(header, &mut self.stream)?;
+                let seqnum = ret.header.seqnum;
+                let urb = self.unlinking.remove(&seqnum).map(|(urb, _)| urb);
+                if self.abandoned.remove(&seqnum) {
+                    if let Some(urb) = urb {
+                        self.completed.remove(&urb);
+                    }
+                } else {
+                    self.unlinked.insert(seqnum, ret);
+                }
+            }
+            command => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unexpected USB/IP command {}", command),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the RET_SUBMIT of seqnum. On timeout the URB is unlinked
+    /// and TimedOut is returned, unless it completed before the unlink.
+    /// A timeout of 0 waits forever like usbfs.
+    pub fn wait(&mut self, seqnum: u32, timeout: TimeoutMillis) -> io::Result<RetSubmit> {
+        let deadline = match timeout.0 {
+            0 => None,
+            ms => Some(Instant::now() + Duration::from_millis(ms as u64)),
+        };
+        loop {
+            if let Some(ret) = self.completed.remove(&seqnum) {
+                return Ok(ret);
+            }
+            if !self.pending.contains_key(&seqnum) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("seqnum {} has not been submitted", seqnum),
+                ));
+            }
+            if !self.poll(deadline)? {
+                return match self.unlink(seqnum)? {
+                    Some(ret) => Ok(ret),
+                    None => Err(Error::new(ErrorKind::TimedOut, "USB/IP transfer timed out")),
+                };
+            }
+            self.read_reply()?;
+        }
+    }
+
+    /// Cancel a submitted URB (USBIP_CMD_UNLINK) and wait up to one second
+    /// for RET_UNLINK. Returns the RET_SUBMIT if the URB completed first.
+    pub fn unlink(&mut self, seqnum: u32) -> io::Result<Option<RetSubmit>> {
+        let is_in = match self.pending.remove(&seqnum) {
+            Some(is_in) => is_in,
+            None => return Ok(self.completed.remove(&seqnum)),
+        };
+        let unlink_seqnum = self.next_seqnum();
+        let cmd = CmdUnlink {
+            header: UsbIpHeader {
+                command: USBIP_CMD_UNLINK,
+                seqnum: unlink_seqnum,
+                devid: self.device.devid(),
+                direction: USBIP_DIR_OUT,
+                ep: 0,
+            },
+            unlink_seqnum: seqnum,
+        };
+        cmd.write(&mut self.stream)?;
+        self.unlinking.insert(unlink_seqnum, (seqnum, is_in));
+        let deadline = Instant::now() + UNLINK_TIMEOUT;
+        while self.unlinked.remove(&unlink_seqnum).is_none() {
+            if !self.poll(Some(deadline))? {
+                // Replies that arrive later are read and dropped
+                self.abandoned.insert(unlink_seqnum);
+                return Err(Error::new(ErrorKind::TimedOut, "No USB/IP RET_UNLINK"));
+            }
+            self.read_reply()?;
+        }
+        Ok(self.completed.remove(&seqnum))
+    }
+
+    /// Submit and wait, a negative status is turned into an error
+    fn transfer(
+        &mut self,
+        ep: u8,
+        setup: [u8; 8],
+        data: &[u8],
+        length: usize,
+        timeout: TimeoutMillis,
+    ) -> io::Result<RetSubmit> {
+        let seqnum = self.submit(ep, setup, data, length)?;
+        let ret = self.wait(seqnum, timeout)?;
+        if ret.status != 0 {
+            return Err(Error::from_raw_os_error(-ret.status));
+        }
+        Ok(ret)
+    }
+
+    fn get_descriptor(&mut self, value: u16, index: u16, length: u16) -> io::Result<Vec<u8>> {
+        let ctrl = self.new_control_in(0x80, 0x06, value, index, length)?;
+        let ctrl = self.control_async_wait(ctrl, TimeoutMillis::from(1000))?;
+        Ok(ctrl.as_slice().to_vec())
+    }
+
+    /// Get descriptor string with id for default interface
+    pub fn get_descriptor_string(&mut self, id: u8) -> io::Result<String> {
+        if id == 0 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "Cannot get descriptor string for zero ID",
+            ));
+        }
+        let data = self.get_descriptor(0x0300 | id as u16, 0, 256)?;
+        Ok(string_from_descriptor(&data, id))
+    }
+
+    /// Read device and configuration descriptors from the device
+    pub fn descriptors(&mut self) -> io::Result<UsbDevice> {
+        let mut bytes = self.get_descriptor(0x0100, 0, 18)?;
+        for i in 0..self.device.num_configurations {
+            let head = self.get_descriptor(0x0200 | i as u16, 0, 9)?;
+            if head.len() < 4 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Short configuration descriptor {}", i),
+                ));
+            }
+            let total = u16::from_le_bytes([head[2], head[3]]);
+            bytes.extend(self.get_descriptor(0x0200 | i as u16, 0, total)?);
+        }
+        let (bus, dev) = (self.device.busnum as u8, self.device.devnum as u8);
+        UsbDevice::from_bytes(bytes, |d| {
+            d.bus_num = bus;
+            d.dev_num = dev;
+            d.manufacturer = self
+                .get_descriptor_string(d.device.imanufacturer)
+                .unwrap_or_default();
+            d.product = self
+                .get_descriptor_string(d.device.iproduct)
+                .unwrap_or_default();
+            d.serial = self
+                .get_descriptor_string(d.device.iserial)
+                .unwrap_or_default();
+        })
+    }
+}
+
+impl UsbCoreDriver for UsbIpClient {
+    fn new_bulk_in(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
+        Ok(BulkTransfer::input(
+            ep,
+            TransferBuffer::heap(buffer_capacity)?,
+        ))
+    }
+
+    fn new_bulk_out(&mut self, ep: u8, buffer_capacity: usize) -> io::Result<BulkTransfer> {
+        Ok(BulkTransfer::output(
+            ep,
+            TransferBuffer::heap(buffer_capacity)?,
+        ))
+    }
+
+    fn new_control(
+        &mut self,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> io::Result<ControlTransfer> {
+        let buffer = TransferBuffer::heap(length as usize + 8)?;
+        ControlTransfer::with_setup(buffer, request_type, request, value, index, length)
+    }
+}
+
+impl UsbCoreBlocking for UsbIpClient {
+    fn bulk_read(&mut self, ep: u8, mem: &mut [u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        let ret = self.transfer(ep | ENDPOINT_IN, [0; 8], &[], mem.len(), timeout)?;
+        let length = ret.data.len().min(mem.len());
+        mem[..length].copy_from_slice(&ret.data[..length]);
+        Ok(length as u32)
+    }
+
+    fn bulk_write(&mut self, ep: u8, mem: &[u8], timeout: TimeoutMillis) -> io::Result<u32> {
+        let ret = self.transfer(ep & 0x7F, [0; 8], mem, 0, timeout)?;
+        Ok(ret.actual_length as u32)
+    }
+
+    fn control_async_wait(
+        &mut self,
+        mut ctrl: ControlTransfer,
+        timeout: TimeoutMillis,
+    ) -> io::Result<ControlTransfer> {
+        let setup = ctrl.setup_packet();
+        let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+        let ret = self.transfer(0, setup, ctrl.request_data(), length, timeout)?;
+        ctrl.set_response(&ret.data, ret.actual_length as u16);
+        Ok(ctrl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Import over a local connection served by script
+    fn client<F>(script: F) -> (UsbIpClient, thread::JoinHandle<()>)
+    where
+        F: FnOnce(&mut TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            OpHeader::read(&mut stream).unwrap();
+            stream.read_exact(&mut [0; 32]).unwrap();
+            OpHeader::new(OP_REP_IMPORT, 0).write(&mut stream).unwrap();
+            UsbIpDevice::default().write(&mut stream, false).unwrap();
+            script(&mut stream);
+        });
+        (UsbIpClient::import(addr, "1-1").unwrap(), server)
+    }
+
+    fn ret_submit(seqnum: u32, data: Vec<u8>) -> RetSubmit {
+        RetSubmit {
+            header: UsbIpHeader {
+                command: USBIP_RET_SUBMIT,
+                seqnum,
+                ..Default::default()
+            },
+            actual_length: data.len() as i32,
+            data,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn completion_racing_with_timeout_unlink_is_returned() {
+        let (mut usb, server) = client(|stream| {
+            let header = UsbIpHeader::read(stream).unwrap();
+            let submit = CmdSubmit::read_body(header, stream).unwrap();
+            let header = UsbIpHeader::read(stream).unwrap();
+            let unlink = CmdUnlink::read_body(header, stream).unwrap();
+            assert_eq!(unlink.unlink_seqnum, submit.header.seqnum);
+            ret_submit(submit.header.seqnum, vec![1, 2, 3])
+                .write(stream, true)
+                .unwrap();
+            RetUnlink {
+                header: UsbIpHeader {
+                    command: USBIP_RET_UNLINK,
+                    seqnum: unlink.header.seqnum,
+                    ..Default::default()
+                },
+                status: 0,
+            }
+            .write(stream)
+            .unwrap();
+        });
+        let seqnum = usb.submit(0x81, [0; 8], &[], 64).unwrap();
+        let ret = usb.wait(seqnum, TimeoutMillis::from(10)).unwrap();
+        assert_eq!(ret.data, vec![1, 2, 3]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn unlink_without_reply_times_out_and_drops_late_replies() {
+        let (mut usb, server) = client(|stream| {
+            let header = UsbIpHeader::read(stream).unwrap();
+            let first = CmdSubmit::read_body(header, stream).unwrap();
+            let header = UsbIpHeader::read(stream).unwrap();
+            let unlink = CmdUnlink::read_body(header, stream).unwrap();
+            let header = UsbIpHeader::read(stream).unwrap();
+            let second = CmdSubmit::read_body(header, stream).unwrap();
+            ret_submit(first.header.seqnum, vec![1])
+                .write(stream, true)
+                .unwrap();
+            RetUnlink {
+                header: UsbIpHeader {
+                    command: USBIP_RET_UNLINK,
+                    seqnum: unlink.header.seqnum,
+                    ..Default::default()
+                },
+                status: 0,
+            }
+            .write(stream)
+            .unwrap();
+            ret_submit(second.header.seqnum, vec![2])
+                .write(stream, true)
+                .unwrap();
+        });
+        let first = usb.submit(0x81, [0; 8], &[], 64).unwrap();
+        let error = usb.wait(first, TimeoutMillis::from(10)).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        let second = usb.submit(0x81, [0; 8], &[], 64).unwrap();
+        let ret = usb.wait(second, TimeoutMillis::from(1000)).unwrap();
+        assert_eq!(ret.data, vec![2]);
+        assert!(usb.completed.is_empty() && usb.unlinking.is_empty());
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,8 @@
+//! USB/IP transport, talk to devices exported over TCP or export local
+//! and emulated devices.
+pub mod client;
+pub mod protocol;
+pub mod server;
+pub use client::UsbIpClient;
+pub use protocol::UsbIpDevice;
+pub use server::{EmulatedDevice, EmulatedExport, UsbFsExport, UsbIpExport, UsbIpServer};
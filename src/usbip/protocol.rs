@@ -0,0 +1,486 @@
+//! USB/IP wire format, see Documentation/usb/usbip_protocol.rst in Linux.
+//! All fields are big endian.
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+
+pub const USBIP_VERSION: u16 = 0x0111;
+pub const USBIP_PORT: u16 = 3240;
+
+pub const OP_REQ_DEVLIST: u16 = 0x8005;
+pub const OP_REP_DEVLIST: u16 = 0x0005;
+pub const OP_REQ_IMPORT: u16 = 0x8003;
+pub const OP_REP_IMPORT: u16 = 0x0003;
+
+pub const USBIP_CMD_SUBMIT: u32 = 1;
+pub const USBIP_CMD_UNLINK: u32 = 2;
+pub const USBIP_RET_SUBMIT: u32 = 3;
+pub const USBIP_RET_UNLINK: u32 = 4;
+
+pub const USBIP_DIR_OUT: u32 = 0;
+pub const USBIP_DIR_IN: u32 = 1;
+
+pub const USBIP_SPEED_UNKNOWN: u32 = 0;
+pub const USBIP_SPEED_LOW: u32 = 1;
+pub const USBIP_SPEED_FULL: u32 = 2;
+pub const USBIP_SPEED_HIGH: u32 = 3;
+pub const USBIP_SPEED_WIRELESS: u32 = 4;
+pub const USBIP_SPEED_SUPER: u32 = 5;
+pub const USBIP_SPEED_SUPER_PLUS: u32 = 6;
+
+/// transfer_flags of CMD_SUBMIT, the URB_* flags of Linux
+pub const URB_SHORT_NOT_OK: u32 = 0x0001;
+pub const URB_ZERO_PACKET: u32 = 0x0040;
+
+/// Largest transfer buffer taken from the peer, the default usbfs_memory_mb
+pub const MAX_TRANSFER_LENGTH: usize = 16 * 1024 * 1024;
+/// Most iso packets taken from the peer, USBIP_MAX_ISO_PACKETS in Linux
+pub const MAX_ISO_PACKETS: usize = 1024;
+
+// Sizes of the fixed strings in struct usbip_usb_device
+const PATH_SIZE: usize = 256;
+const BUSID_SIZE: usize = 32;
+
+pub(crate) fn read_u8<R: Read>(r: &mut R) -> io::Result<u8> {
+    let mut b = [0; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+pub(crate) fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut b = [0; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+pub(crate) fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_be_bytes(b))
+}
+
+fn read_i32<R: Read>(r: &mut R) -> io::Result<i32> {
+    Ok(read_u32(r)? as i32)
+}
+
+fn read_string<R: Read>(r: &mut R, size: usize) -> io::Result<String> {
+    let mut b = vec![0; size];
+    r.read_exact(&mut b)?;
+    let end = b.iter().position(|c| *c == 0).unwrap_or(size);
+    Ok(String::from_utf8_lossy(&b[..end]).into_owned())
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str, size: usize) -> io::Result<()> {
+    let mut b = vec![0; size];
+    // Keep room for the terminating zero
+    let length = s.len().min(size - 1);
+    b[..length].copy_from_slice(&s.as_bytes()[..length]);
+    w.write_all(&b)
+}
+
+/// Length sent by the peer, checked before anything is allocated for it
+fn check_length(length: i32) -> io::Result<usize> {
+    if length < 0 || length as usize > MAX_TRANSFER_LENGTH {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Invalid USB/IP buffer length {}", length),
+        ));
+    }
+    Ok(length as usize)
+}
+
+fn read_data<R: Read>(r: &mut R, length: i32) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; check_length(length)?];
+    r.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Header of the OP_* messages used before a device is imported
+#[derive(Debug, Clone, PartialEq)]
+pub struct OpHeader {
+    pub version: u16,
+    pub code: u16,
+    pub status: u32,
+}
+
+impl OpHeader {
+    pub fn new(code: u16, status: u32) -> Self {
+        Self {
+            version: USBIP_VERSION,
+            code,
+            status,
+        }
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            version: read_u16(r)?,
+            code: read_u16(r)?,
+            status: read_u32(r)?,
+        })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.version.to_be_bytes())?;
+        w.write_all(&self.code.to_be_bytes())?;
+        w.write_all(&self.status.to_be_bytes())
+    }
+
+    /// Check this is a reply with the expected code and OK status
+    pub(crate) fn expect(&self, code: u16) -> io::Result<()> {
+        if self.code != code {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unexpected USB/IP reply 0x{:04x} expected 0x{:04x}",
+                    self.code, code
+                ),
+            ));
+        }
+        if self.status != 0 {
+            return Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "USB/IP request 0x{:04x} failed with status {}",
+                    code, self.status
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsbIpInterface {
+    pub interface_class: u8,
+    pub interface_sub_class: u8,
+    pub interface_protocol: u8,
+}
+
+/// Exported device as listed by OP_REP_DEVLIST and OP_REP_IMPORT
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsbIpDevice {
+    /// sysfs path on the server
+    pub path: String,
+    /// Used to import the device, eg. 1-2.3
+    pub busid: String,
+    pub busnum: u32,
+    pub devnum: u32,
+    /// USBIP_SPEED_*
+    pub speed: u32,
+    pub id_vendor: u16,
+    pub id_product: u16,
+    pub bcd_device: u16,
+    pub device_class: u8,
+    pub device_sub_class: u8,
+    pub device_protocol: u8,
+    pub configuration_value: u8,
+    pub num_configurations: u8,
+    pub num_interfaces: u8,
+    /// Only filled in device lists
+    pub interfaces: Vec<UsbIpInterface>,
+}
+
+impl UsbIpDevice {
+    /// devid used in USBIP_CMD_* headers
+    pub fn devid(&self) -> u32 {
+        self.busnum << 16 | self.devnum
+    }
+
+    pub fn read<R: Read>(r: &mut R, with_interfaces: bool) -> io::Result<Self> {
+        let mut dev = Self {
+            path: read_string(r, PATH_SIZE)?,
+            busid: read_string(r, BUSID_SIZE)?,
+            busnum: read_u32(r)?,
+            devnum: read_u32(r)?,
+            speed: read_u32(r)?,
+            id_vendor: read_u16(r)?,
+            id_product: read_u16(r)?,
+            bcd_device: read_u16(r)?,
+            device_class: read_u8(r)?,
+            device_sub_class: read_u8(r)?,
+            device_protocol: read_u8(r)?,
+            configuration_value: read_u8(r)?,
+            num_configurations: read_u8(r)?,
+            num_interfaces: read_u8(r)?,
+            interfaces: vec![],
+        };
+        if with_interfaces {
+            for _ in 0..dev.num_interfaces {
+                dev.interfaces.push(UsbIpInterface {
+                    interface_class: read_u8(r)?,
+                    interface_sub_class: read_u8(r)?,
+                    interface_protocol: read_u8(r)?,
+                });
+                // padding
+                read_u8(r)?;
+            }
+        }
+        Ok(dev)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W, with_interfaces: bool) -> io::Result<()> {
+        write_string(w, &self.path, PATH_SIZE)?;
+        write_string(w, &self.busid, BUSID_SIZE)?;
+        w.write_all(&self.busnum.to_be_bytes())?;
+        w.write_all(&self.devnum.to_be_bytes())?;
+        w.write_all(&self.speed.to_be_bytes())?;
+        w.write_all(&self.id_vendor.to_be_bytes())?;
+        w.write_all(&self.id_product.to_be_bytes())?;
+        w.write_all(&self.bcd_device.to_be_bytes())?;
+        w.write_all(&[
+            self.device_class,
+            self.device_sub_class,
+            self.device_protocol,
+            self.configuration_value,
+            self.num_configurations,
+            self.interfaces.len().max(self.num_interfaces as usize) as u8,
+        ])?;
+        if with_interfaces {
+            for iface in &self.interfaces {
+                w.write_all(&[
+                    iface.interface_class,
+                    iface.interface_sub_class,
+                    iface.interface_protocol,
+                    0,
+                ])?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// usbip_header_basic, common to all USBIP_CMD_* and USBIP_RET_* messages
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UsbIpHeader {
+    pub command: u32,
+    pub seqnum: u32,
+    pub devid: u32,
+    /// USBIP_DIR_IN or USBIP_DIR_OUT
+    pub direction: u32,
+    /// Endpoint number without the direction bit
+    pub ep: u32,
+}
+
+impl UsbIpHeader {
+    pub fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        Ok(Self {
+            command: read_u32(r)?,
+            seqnum: read_u32(r)?,
+            devid: read_u32(r)?,
+            direction: read_u32(r)?,
+            ep: read_u32(r)?,
+        })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.command.to_be_bytes())?;
+        w.write_all(&self.seqnum.to_be_bytes())?;
+        w.write_all(&self.devid.to_be_bytes())?;
+        w.write_all(&self.direction.to_be_bytes())?;
+        w.write_all(&self.ep.to_be_bytes())
+    }
+
+    pub fn is_in(&self) -> bool {
+        self.direction == USBIP_DIR_IN
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IsoPacket {
+    pub offset: u32,
+    pub length: u32,
+    pub actual_length: u32,
+    pub status: u32,
+}
+
+impl IsoPacket {
+    fn read_all<R: Read>(r: &mut R, number_of_packets: i32) -> io::Result<Vec<Self>> {
+        // Non iso transfers use 0 or -1 (0xffffffff)
+        let n = number_of_packets.max(0);
+        if n as usize > MAX_ISO_PACKETS {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Too many USB/IP iso packets {}", n),
+            ));
+        }
+        let mut packets = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            packets.push(IsoPacket {
+                offset: read_u32(r)?,
+                length: read_u32(r)?,
+                actual_length: read_u32(r)?,
+                status: read_u32(r)?,
+            });
+        }
+        Ok(packets)
+    }
+
+    fn write_all<W: Write>(w: &mut W, packets: &[Self]) -> io::Result<()> {
+        for p in packets {
+            w.write_all(&p.offset.to_be_bytes())?;
+            w.write_all(&p.length.to_be_bytes())?;
+            w.write_all(&p.actual_length.to_be_bytes())?;
+            w.write_all(&p.status.to_be_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// USBIP_CMD_SUBMIT, data is only present for OUT transfers
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CmdSubmit {
+    pub header: UsbIpHeader,
+    pub transfer_flags: u32,
+    pub transfer_buffer_length: i32,
+    pub start_frame: i32,
+    pub number_of_packets: i32,
+    pub interval: i32,
+    pub setup: [u8; 8],
+    pub data: Vec<u8>,
+    pub iso_packets: Vec<IsoPacket>,
+}
+
+impl CmdSubmit {
+    /// Read the message after the header has been read
+    pub fn read_body<R: Read>(header: UsbIpHeader, r: &mut R) -> io::Result<Self> {
+        let mut cmd = Self {
+            header,
+            transfer_flags: read_u32(r)?,
+            transfer_buffer_length: read_i32(r)?,
+            start_frame: read_i32(r)?,
+            number_of_packets: read_i32(r)?,
+            interval: read_i32(r)?,
+            ..Default::default()
+        };
+        // IN buffers are allocated by the receiver
+        if cmd.header.is_in() {
+            check_length(cmd.transfer_buffer_length.max(0))?;
+        }
+        r.read_exact(&mut cmd.setup)?;
+        if !cmd.header.is_in() {
+            cmd.data = read_data(r, cmd.transfer_buffer_length)?;
+        }
+        cmd.iso_packets = IsoPacket::read_all(r, cmd.number_of_packets)?;
+        Ok(cmd)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut b = Vec::with_capacity(48 + self.data.len());
+        self.header.write(&mut b)?;
+        b.extend_from_slice(&self.transfer_flags.to_be_bytes());
+        b.extend_from_slice(&self.transfer_buffer_length.to_be_bytes());
+        b.extend_from_slice(&self.start_frame.to_be_bytes());
+        b.extend_from_slice(&self.number_of_packets.to_be_bytes());
+        b.extend_from_slice(&self.interval.to_be_bytes());
+        b.extend_from_slice(&self.setup);
+        if !self.header.is_in() {
+            b.extend_from_slice(&self.data);
+        }
+        IsoPacket::write_all(&mut b, &self.iso_packets)?;
+        w.write_all(&b)
+    }
+}
+
+/// USBIP_RET_SUBMIT, data is only present for IN transfers.
+/// The header of a reply has direction and ep set to 0 so the receiver
+/// must remember the direction of the seqnum it submitted.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetSubmit {
+    pub header: UsbIpHeader,
+    /// 0 or a negative errno
+    pub status: i32,
+    pub actual_length: i32,
+    pub start_frame: i32,
+    pub number_of_packets: i32,
+    pub error_count: i32,
+    pub data: Vec<u8>,
+    pub iso_packets: Vec<IsoPacket>,
+}
+
+impl RetSubmit {
+    /// Read the message after the header has been read
+    pub fn read_body<R: Read>(header: UsbIpHeader, r: &mut R, is_in: bool) -> io::Result<Self> {
+        let mut ret = Self {
+            header,
+            status: read_i32(r)?,
+            actual_length: read_i32(r)?,
+            start_frame: read_i32(r)?,
+            number_of_packets: read_i32(r)?,
+            error_count: read_i32(r)?,
+            ..Default::default()
+        };
+        // padding (setup in CMD_SUBMIT)
+        r.read_exact(&mut [0; 8])?;
+        if is_in {
+            ret.data = read_data(r, ret.actual_length)?;
+        }
+        ret.iso_packets = IsoPacket::read_all(r, ret.number_of_packets)?;
+        Ok(ret)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W, is_in: bool) -> io::Result<()> {
+        let mut b = Vec::with_capacity(48 + self.data.len());
+        self.header.write(&mut b)?;
+        b.extend_from_slice(&self.status.to_be_bytes());
+        b.extend_from_slice(&self.actual_length.to_be_bytes());
+        b.extend_from_slice(&self.start_frame.to_be_bytes());
+        b.extend_from_slice(&self.number_of_packets.to_be_bytes());
+        b.extend_from_slice(&self.error_count.to_be_bytes());
+        b.extend_from_slice(&[0; 8]);
+        if is_in {
+            b.extend_from_slice(&self.data);
+        }
+        IsoPacket::write_all(&mut b, &self.iso_packets)?;
+        w.write_all(&b)
+    }
+}
+
+/// USBIP_CMD_UNLINK
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CmdUnlink {
+    pub header: UsbIpHeader,
+    pub unlink_seqnum: u32,
+}
+
+impl CmdUnlink {
+    pub fn read_body<R: Read>(header: UsbIpHeader, r: &mut R) -> io::Result<Self> {
+        let unlink_seqnum = read_u32(r)?;
+        r.read_exact(&mut [0; 24])?;
+        Ok(Self {
+            header,
+            unlink_seqnum,
+        })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut b = Vec::with_capacity(48);
+        self.header.write(&mut b)?;
+        b.extend_from_slice(&self.unlink_seqnum.to_be_bytes());
+        b.extend_from_slice(&[0; 24]);
+        w.write_all(&b)
+    }
+}
+
+/// USBIP_RET_UNLINK, status is -ECONNRESET if the URB was unlinked and 0
+/// if it already completed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetUnlink {
+    pub header: UsbIpHeader,
+    pub status: i32,
+}
+
+impl RetUnlink {
+    pub fn read_body<R: Read>(header: UsbIpHeader, r: &mut R) -> io::Result<Self> {
+        let status = read_i32(r)?;
+        r.read_exact(&mut [0; 24])?;
+        Ok(Self { header, status })
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut b = Vec::with_capacity(48);
+        self.header.write(&mut b)?;
+        b.extend_from_slice(&self.status.to_be_bytes());
+        b.extend_from_slice(&[0; 24]);
+        w.write_all(&b)
+    }
+}
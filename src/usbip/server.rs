@@ -0,0 +1,742 @@
+use super::protocol::*;
+use crate::descriptors::descriptor::string_to_descriptor;
+use crate::descriptors::device::Device;
+use crate::request::*;
+use crate::usb_transfer::TransferKind;
+use crate::{UsbCore, UsbCoreDriver, ENDPOINT_IN};
+use nix::poll::{poll, PollFd, PollFlags};
+use std::collections::HashMap;
+use std::io;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+/// How often completions() is called for exports without a file descriptor
+const POLL_INTERVAL_MS: i32 = 10;
+
+/// A device that can be exported by UsbIpServer
+pub trait UsbIpExport {
+    /// Device information sent in OP_REP_DEVLIST and OP_REP_IMPORT
+    fn info(&self) -> UsbIpDevice;
+    /// Start a URB, the result is given back by completions().
+    /// An error is sent back to the client as a failed RET_SUBMIT.
+    fn submit(&mut self, cmd: CmdSubmit) -> io::Result<()>;
+    /// Cancel a URB, returns false if it is not pending anymore
+    fn unlink(&mut self, seqnum: u32) -> io::Result<bool>;
+    /// Take completed URBs without blocking, header.seqnum must be set
+    fn completions(&mut self) -> io::Result<Vec<RetSubmit>>;
+    /// Called when the client disconnects, pending URBs should be dropped
+    fn detach(&mut self) {}
+    /// File descriptor that becomes readable or writable when there are
+    /// completions. If None completions() is polled on an interval.
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// Guess connection speed from descriptors
+fn speed_from_descriptors(device: &Device) -> u32 {
+    if device.bcd_usb >= 0x0300 {
+        return USBIP_SPEED_SUPER;
+    }
+    if device.bcd_usb < 0x0200 {
+        return USBIP_SPEED_FULL;
+    }
+    let high_speed = device.configurations.iter().any(|c| {
+        c.interfaces.iter().any(|i| {
+            i.endpoints
+                .iter()
+                .any(|e| e.bm_attributes & 0x03 == 0x02 && e.max_packet_size == 512)
+        })
+    });
+    if high_speed {
+        USBIP_SPEED_HIGH
+    } else {
+        USBIP_SPEED_FULL
+    }
+}
+
+fn device_info(
+    device: &Device,
+    busid: String,
+    path: String,
+    busnum: u32,
+    devnum: u32,
+) -> UsbIpDevice {
+    let (configuration_value, interfaces) = match device.configurations.first() {
+        Some(conf) => (
+            conf.configuration_value(),
+            conf.interfaces
+                .iter()
+                .filter(|i| i.alternate_setting == 0)
+                .map(|i| UsbIpInterface {
+                    interface_class: i.interface_class,
+                    interface_sub_class: i.interface_sub_class,
+                    interface_protocol: i.interface_protocol,
+                })
+                .collect(),
+        ),
+        None => (0, vec![]),
+    };
+    UsbIpDevice {
+        path,
+        busid,
+        busnum,
+        devnum,
+        speed: speed_from_descriptors(device),
+        id_vendor: device.id_vendor,
+        id_product: device.id_product,
+        bcd_device: device.bcd_device,
+        device_class: device.device_class,
+        device_sub_class: device.device_sub_class,
+        device_protocol: device.device_protocol,
+        configuration_value,
+        num_configurations: device.configurations.len() as u8,
+        num_interfaces: interfaces.len() as u8,
+        interfaces,
+    }
+}
+
+fn ret_submit(seqnum: u32, result: Result<Vec<u8>, i32>, out_length: usize) -> RetSubmit {
+    let mut ret = RetSubmit {
+        header: UsbIpHeader {
+            seqnum,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    match result {
+        Ok(data) => {
+            ret.actual_length = if data.is_empty() {
+                out_length
+            } else {
+                data.len()
+            } as i32;
+            ret.data = data;
+        }
+        Err(status) => ret.status = status,
+    }
+    ret
+}
+
+/// Serve exported devices to USB/IP clients such as the vhci-hcd driver
+/// (`usbip attach -r host -b busid`) or UsbIpClient.
+/// Connections are served one at a time.
+///
+/// Example:
+/// ```ignore
+/// let mut usb = UsbCore::from_bus_device(1, 4)?;
+/// usb.claim_interface(0)?;
+/// let mut server = UsbIpServer::new();
+/// server.export(UsbFsExport::new(usb)?);
+/// server.serve(("0.0.0.0", USBIP_PORT))?;
+/// ```
+#[derive(Default)]
+pub struct UsbIpServer {
+    exports: Vec<Box<dyn UsbIpExport>>,
+}
+
+impl UsbIpServer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn export<E: UsbIpExport + 'static>(&mut self, export: E) {
+        self.exports.push(Box::new(export));
+    }
+
+    /// Accept connections forever
+    pub fn serve<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            if let Err(e) = self.handle(stream?) {
+                log::warn!("USB/IP connection failed: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve one connection, returns when the client disconnects
+    pub fn handle(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let op = OpHeader::read(&mut stream)?;
+        match op.code {
+            OP_REQ_DEVLIST => {
+                let mut rep = Vec::new();
+                OpHeader::new(OP_REP_DEVLIST, 0).write(&mut rep)?;
+                rep.extend_from_slice(&(self.exports.len() as u32).to_be_bytes());
+                for export in &self.exports {
+                    export.info().write(&mut rep, true)?;
+                }
+                stream.write_all(&rep)
+            }
+            OP_REQ_IMPORT => {
+                let mut busid = [0; 32];
+                stream.read_exact(&mut busid)?;
+                let end = busid.iter().position(|c| *c == 0).unwrap_or(busid.len());
+                let busid = String::from_utf8_lossy(&busid[..end]).into_owned();
+                let export = match self.exports.iter_mut().find(|e| e.info().busid == busid) {
+                    Some(export) => export,
+                    None => {
+                        log::warn!("USB/IP import of unknown busid {}", busid);
+                        return OpHeader::new(OP_REP_IMPORT, 1).write(&mut stream);
+                    }
+                };
+                let mut rep = Vec::new();
+                OpHeader::new(OP_REP_IMPORT, 0).write(&mut rep)?;
+                export.info().write(&mut rep, false)?;
+                stream.write_all(&rep)?;
+                log::info!("USB/IP device {} imported", busid);
+                let res = Self::forward(export.as_mut(), &mut stream);
+                export.detach();
+                res
+            }
+            code => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown USB/IP operation 0x{:04x}", code),
+            )),
+        }
+    }
+
+    /// Pass URBs between client and export until the client disconnects
+    fn forward(export: &mut dyn UsbIpExport, stream: &mut TcpStream) -> io::Result<()> {
+        // direction of pending URBs by seqnum
+        let mut pending: HashMap<u32, bool> = HashMap::new();
+        loop {
+            let mut fds = vec![PollFd::new(stream.as_raw_fd(), PollFlags::POLLIN)];
+            if let Some(fd) = export.as_raw_fd() {
+                fds.push(PollFd::new(fd, PollFlags::POLLIN | PollFlags::POLLOUT));
+            }
+            let timeout = if fds.len() == 1 && !pending.is_empty() {
+                POLL_INTERVAL_MS
+            } else {
+                -1
+            };
+            poll(&mut fds, timeout).map_err(|_| io::Error::last_os_error())?;
+            let readable = fds[0].revents().map(|r| !r.is_empty()).unwrap_or(false);
+            if readable {
+                let header = match UsbIpHeader::read(stream) {
+                    Ok(header) => header,
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                match header.command {
+                    USBIP_CMD_SUBMIT => {
+                        let cmd = CmdSubmit::read_body(header, stream)?;
+                        let (seqnum, is_in) = (cmd.header.seqnum, cmd.header.is_in());
+                        pending.insert(seqnum, is_in);
+                        if let Err(e) = export.submit(cmd) {
+                            log::debug!("USB/IP submit {} failed: {}", seqnum, e);
+                            let errno = e.raw_os_error().unwrap_or(libc::EINVAL);
+                            let ret = ret_submit(seqnum, Err(-errno), 0);
+                            pending.remove(&seqnum);
+                            Self::reply(stream, ret, is_in)?;
+                        }
+                    }
+                    USBIP_CMD_UNLINK => {
+                        let cmd = CmdUnlink::read_body(header, stream)?;
+                        Self::unlink(export, stream, &mut pending, cmd)?;
+                    }
+                    command => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Unexpected USB/IP command {}", command),
+                        ));
+                    }
+                }
+            }
+            Self::send_completions(export, stream, &mut pending)?;
+        }
+    }
+
+    /// Answer CMD_UNLINK. A URB that completed but is not sent yet gets its
+    /// RET_SUBMIT first, the client forgets the URB on RET_UNLINK status 0.
+    fn unlink<W: Write>(
+        export: &mut dyn UsbIpExport,
+        stream: &mut W,
+        pending: &mut HashMap<u32, bool>,
+        cmd: CmdUnlink,
+    ) -> io::Result<()> {
+        let unlinked =
+            pending.contains_key(&cmd.unlink_seqnum) && export.unlink(cmd.unlink_seqnum)?;
+        if unlinked {
+            pending.remove(&cmd.unlink_seqnum);
+        } else if pending.contains_key(&cmd.unlink_seqnum) {
+            Self::send_completions(export, stream, pending)?;
+        }
+        RetUnlink {
+            header: UsbIpHeader {
+                command: USBIP_RET_UNLINK,
+                seqnum: cmd.header.seqnum,
+                ..Default::default()
+            },
+            status: if unlinked { -libc::ECONNRESET } else { 0 },
+        }
+        .write(stream)
+    }
+
+    fn send_completions<W: Write>(
+        export: &mut dyn UsbIpExport,
+        stream: &mut W,
+        pending: &mut HashMap<u32, bool>,
+    ) -> io::Result<()> {
+        for ret in export.completions()? {
+            if let Some(is_in) = pending.remove(&ret.header.seqnum) {
+                Self::reply(stream, ret, is_in)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn reply<W: Write>(stream: &mut W, mut ret: RetSubmit, is_in: bool) -> io::Result<()> {
+        ret.header = UsbIpHeader {
+            command: USBIP_RET_SUBMIT,
+            seqnum: ret.header.seqnum,
+            ..Default::default()
+        };
+        if !is_in {
+            ret.data.clear();
+        }
+        ret.write(stream, is_in)
+    }
+}
+
+/// Export a device opened with UsbCore, URBs are forwarded to usbfs.
+/// Claim the interfaces the client will use before exporting.
+pub struct UsbFsExport {
+    usb: UsbCore,
+    info: UsbIpDevice,
+    // transfer buffer address to seqnum of URBs in flight
+    in_flight: HashMap<usize, u32>,
+    // requests answered without asking the device
+    ready: Vec<RetSubmit>,
+}
+
+impl UsbFsExport {
+    /// The busid is "bus-dev" as used by UsbEnumerate
+    pub fn new(mut usb: UsbCore) -> io::Result<Self> {
+        let (bus, dev) = usb.bus_dev;
+        let device = match usb.descriptors() {
+            Some(device) => &device.device,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::Other,
+                    format!("Could not read descriptors of {}-{}", bus, dev),
+                ));
+            }
+        };
+        let info = device_info(
+            device,
+            format!("{}-{}", bus, dev),
+            format!("/dev/bus/usb/{:03}/{:03}", bus, dev),
+            bus as u32,
+            dev as u32,
+        );
+        Ok(Self {
+            usb,
+            info,
+            in_flight: HashMap::new(),
+            ready: vec![],
+        })
+    }
+
+    /// Change busid or speed reported to clients
+    pub fn info_mut(&mut self) -> &mut UsbIpDevice {
+        &mut self.info
+    }
+
+    /// Requests usbfs must handle itself instead of passing them on
+    fn intercept(&mut self, setup: &[u8; 8]) -> Option<io::Result<()>> {
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let index = u16::from_le_bytes([setup[4], setup[5]]);
+        match (setup[0], setup[1]) {
+            // SET_CONFIGURATION, already configured by the host kernel
+            (0x00, REQUEST_SET_CONFIGURATION) => {
+                log::debug!("USB/IP SET_CONFIGURATION {} ignored", value);
+                Some(Ok(()))
+            }
+            // SET_INTERFACE
+            (0x01, REQUEST_SET_INTERFACE) => {
+                Some(self.usb.set_interface(index as u32, value as u32))
+            }
+            // CLEAR_FEATURE(ENDPOINT_HALT)
+            (0x02, REQUEST_CLEAR_FEATURE) if value == FEATURE_ENDPOINT_HALT => {
+                Some(self.usb.clear_halt(index as u8))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl UsbIpExport for UsbFsExport {
+    fn info(&self) -> UsbIpDevice {
+        self.info.clone()
+    }
+
+    fn submit(&mut self, cmd: CmdSubmit) -> io::Result<()> {
+        let seqnum = cmd.header.seqnum;
+        let ep = cmd.header.ep as u8;
+        let length = cmd.transfer_buffer_length.max(0) as usize;
+        if cmd.number_of_packets > 0 {
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+        let buffer = if ep == 0 {
+            if let Some(res) = self.intercept(&cmd.setup) {
+                res?;
+                self.ready.push(ret_submit(seqnum, Ok(vec![]), 0));
+                return Ok(());
+            }
+            let s = &cmd.setup;
+            let value = u16::from_le_bytes([s[2], s[3]]);
+            let index = u16::from_le_bytes([s[4], s[5]]);
+            let ctrl = if cmd.header.is_in() {
+                if length > u16::MAX as usize {
+                    return Err(io::Error::from_raw_os_error(libc::EINVAL));
+                }
+                self.usb
+                    .new_control_in(s[0], s[1], value, index, length as u16)?
+            } else {
+                self.usb
+                    .new_control_out(s[0], s[1], value, index, &cmd.data)?
+            };
+            let buffer = ctrl.buffer_address();
+            self.usb.submit_control(ctrl)?;
+            buffer
+        } else if cmd.header.is_in() {
+            let mut bulk = self.usb.new_bulk_in(ep, length.max(1))?;
+            bulk.set_short_not_ok(cmd.transfer_flags & URB_SHORT_NOT_OK != 0);
+            let buffer = bulk.buffer_address();
+            self.usb.submit_bulk(bulk)?;
+            buffer
+        } else {
+            let mut bulk = self.usb.new_bulk_out(ep, cmd.data.len().max(1))?;
+            bulk.write_all(&cmd.data)?;
+            bulk.set_zero_packet(cmd.transfer_flags & URB_ZERO_PACKET != 0);
+            let buffer = bulk.buffer_address();
+            self.usb.submit_bulk(bulk)?;
+            buffer
+        };
+        self.in_flight.insert(buffer, seqnum);
+        Ok(())
+    }
+
+    fn unlink(&mut self, seqnum: u32) -> io::Result<bool> {
+        let buffer = match self.in_flight.iter().find(|(_, s)| **s == seqnum) {
+            Some((buffer, _)) => *buffer,
+            None => return Ok(false),
+        };
+        match self.usb.discard_urb(buffer) {
+            Ok(()) => {}
+            // Completed but not reaped yet, it is sent back by completions()
+            Err(e) if e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(libc::EINVAL) => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        }
+        // Reaped later with an error status and dropped since it is not in flight
+        self.in_flight.remove(&buffer);
+        Ok(true)
+    }
+
+    fn completions(&mut self) -> io::Result<Vec<RetSubmit>> {
+        let mut rets: Vec<RetSubmit> = self.ready.drain(..).collect();
+        self.usb.async_response_all()?;
+        for transfer in self.usb.collect_responses() {
+            let (buffer, status, actual_length, data) = match &transfer {
+                TransferKind::Bulk(bulk) => (
+                    bulk.buffer_address(),
+                    bulk.status,
+                    bulk.actual_length,
+                    if bulk.endpoint.is_bulk_in() {
+                        bulk.as_slice().to_vec()
+                    } else {
+                        vec![]
+                    },
+                ),
+                TransferKind::Control(control) => (
+                    control.buffer_address(),
+                    control.status,
+                    control.actual_length as usize,
+                    control.as_slice().to_vec(),
+                ),
+                TransferKind::Invalid(ep) => {
+                    log::error!("USB/IP export got invalid transfer {}", ep);
+                    continue;
+                }
+            };
+            if let Some(seqnum) = self.in_flight.remove(&buffer) {
+                let mut ret = ret_submit(seqnum, Ok(data), actual_length);
+                ret.status = status;
+                ret.actual_length = actual_length as i32;
+                rets.push(ret);
+            }
+        }
+        Ok(rets)
+    }
+
+    fn detach(&mut self) {
+        for buffer in self.in_flight.keys().cloned().collect::<Vec<_>>() {
+            let _ = self.usb.discard_urb(buffer);
+        }
+        self.in_flight.clear();
+        self.ready.clear();
+    }
+
+    fn as_raw_fd(&self) -> Option<RawFd> {
+        Some(self.usb.handle().as_raw_fd())
+    }
+}
+
+/// Behaviour of a software emulated device. Standard requests like
+/// GET_DESCRIPTOR and SET_CONFIGURATION are answered by EmulatedExport
+/// from descriptor(). Errors are negative errno values, eg. -EPIPE for stall.
+pub trait EmulatedDevice {
+    /// Descriptors including configurations, interfaces and endpoints
+    fn descriptor(&self) -> &Device;
+
+    /// String descriptor for id, the language id 0 is handled by EmulatedExport
+    fn string(&self, _id: u8) -> Option<String> {
+        None
+    }
+
+    /// Class and vendor control requests. Return data for IN requests.
+    fn control(&mut self, _setup: [u8; 8], _data: &[u8]) -> Result<Vec<u8>, i32> {
+        Err(-libc::EPIPE)
+    }
+
+    /// Data written by the host, return number of bytes accepted
+    fn bulk_out(&mut self, _ep: u8, data: &[u8]) -> Result<usize, i32> {
+        Ok(data.len())
+    }
+
+    /// Data to the host, Ok(None) keeps the URB pending and it is asked again later
+    fn bulk_in(&mut self, _ep: u8, _length: usize) -> Result<Option<Vec<u8>>, i32> {
+        Ok(None)
+    }
+}
+
+/// Export a software device through USB/IP
+///
+/// Example:
+/// ```ignore
+/// struct Echo { device: Device, buffer: Vec<u8> }
+/// impl EmulatedDevice for Echo { ... }
+/// server.export(EmulatedExport::new(echo, "1-1"));
+/// ```
+pub struct EmulatedExport<D: EmulatedDevice> {
+    device: D,
+    info: UsbIpDevice,
+    configuration: u8,
+    pending_in: Vec<CmdSubmit>,
+    ready: Vec<RetSubmit>,
+}
+
+impl<D: EmulatedDevice> EmulatedExport<D> {
+    pub fn new(device: D, busid: &str) -> Self {
+        let info = device_info(
+            device.descriptor(),
+            busid.into(),
+            format!("/sys/devices/usbip/{}", busid),
+            1,
+            1,
+        );
+        Self {
+            configuration: info.configuration_value,
+            device,
+            info,
+            pending_in: vec![],
+            ready: vec![],
+        }
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        &mut self.device
+    }
+
+    /// Change busid, bus/dev numbers or speed reported to clients
+    pub fn info_mut(&mut self) -> &mut UsbIpDevice {
+        &mut self.info
+    }
+
+    fn standard_request(&mut self, setup: &[u8; 8]) -> Option<Result<Vec<u8>, i32>> {
+        let value = u16::from_le_bytes([setup[2], setup[3]]);
+        let res = match (setup[0], setup[1]) {
+            // GET_STATUS
+            (0x80..=0x82, REQUEST_GET_STATUS) => Ok(vec![0, 0]),
+            // CLEAR_FEATURE and SET_FEATURE
+            (0x00..=0x02, REQUEST_CLEAR_FEATURE) | (0x00..=0x02, REQUEST_SET_FEATURE) => Ok(vec![]),
+            // GET_DESCRIPTOR
+            (0x80, REQUEST_GET_DESCRIPTOR) => {
+                let index = (value & 0xFF) as u8;
+                let device = self.device.descriptor();
+                match value >> 8 {
+                    1 => Ok(device.to_bytes()),
+                    2 => device
+                        .configurations
+                        .get(index as usize)
+                        .map(|c| c.to_bytes())
+                        .ok_or(-libc::EPIPE),
+                    // Language id US English
+                    3 if index == 0 => Ok(vec![4, 3, 0x09, 0x04]),
+                    3 => self
+                        .device
+                        .string(index)
+                        .map(|s| string_to_descriptor(&s))
+                        .ok_or(-libc::EPIPE),
+                    _ => Err(-libc::EPIPE),
+                }
+            }
+            // GET_CONFIGURATION
+            (0x80, REQUEST_GET_CONFIGURATION) => Ok(vec![self.configuration]),
+            // SET_CONFIGURATION
+            (0x00, REQUEST_SET_CONFIGURATION) => {
+                self.configuration = value as u8;
+                Ok(vec![])
+            }
+            // GET_INTERFACE
+            (0x81, REQUEST_GET_INTERFACE) => Ok(vec![0]),
+            // SET_INTERFACE
+            (0x01, REQUEST_SET_INTERFACE) => Ok(vec![]),
+            _ => return None,
+        };
+        Some(res)
+    }
+}
+
+impl<D: EmulatedDevice> UsbIpExport for EmulatedExport<D> {
+    fn info(&self) -> UsbIpDevice {
+        self.info.clone()
+    }
+
+    fn submit(&mut self, cmd: CmdSubmit) -> io::Result<()> {
+        let seqnum = cmd.header.seqnum;
+        let length = cmd.transfer_buffer_length.max(0) as usize;
+        if cmd.header.ep == 0 {
+            let res = match self.standard_request(&cmd.setup) {
+                Some(res) => res,
+                None => self.device.control(cmd.setup, &cmd.data),
+            };
+            let res = res.map(|mut data| {
+                data.truncate(length);
+                data
+            });
+            self.ready.push(ret_submit(seqnum, res, cmd.data.len()));
+        } else if cmd.header.is_in() {
+            self.pending_in.push(cmd);
+        } else {
+            let ep = cmd.header.ep as u8;
+            let res = self.device.bulk_out(ep, &cmd.data);
+            let mut ret = ret_submit(seqnum, res.map(|_| vec![]), 0);
+            if let Ok(length) = res {
+                ret.actual_length = length as i32;
+            }
+            self.ready.push(ret);
+        }
+        Ok(())
+    }
+
+    fn unlink(&mut self, seqnum: u32) -> io::Result<bool> {
+        let before = self.pending_in.len();
+        self.pending_in.retain(|cmd| cmd.header.seqnum != seqnum);
+        Ok(self.pending_in.len() != before)
+    }
+
+    fn completions(&mut self) -> io::Result<Vec<RetSubmit>> {
+        let mut rets: Vec<RetSubmit> = self.ready.drain(..).collect();
+        // Keep order per endpoint, stop asking an endpoint once it has no data
+        let mut waiting: Vec<u32> = vec![];
+        let mut pending = vec![];
+        for cmd in self.pending_in.drain(..) {
+            let ep = cmd.header.ep;
+            if waiting.contains(&ep) {
+                pending.push(cmd);
+                continue;
+            }
+            let length = cmd.transfer_buffer_length.max(0) as usize;
+            match self.device.bulk_in(ep as u8 | ENDPOINT_IN, length) {
+                Ok(None) => {
+                    waiting.push(ep);
+                    pending.push(cmd);
+                }
+                Ok(Some(mut data)) => {
+                    data.truncate(length);
+                    rets.push(ret_submit(cmd.header.seqnum, Ok(data), 0));
+                }
+                Err(status) => rets.push(ret_submit(cmd.header.seqnum, Err(status), 0)),
+            }
+        }
+        self.pending_in = pending;
+        Ok(rets)
+    }
+
+    fn detach(&mut self) {
+        self.pending_in.clear();
+        self.ready.clear();
+        self.configuration = self.info.configuration_value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Sink {
+        device: Device,
+    }
+
+    impl EmulatedDevice for Sink {
+        fn descriptor(&self) -> &Device {
+            &self.device
+        }
+    }
+
+    fn export() -> EmulatedExport<Sink> {
+        let bytes = [
+            18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x09, 0x12, 1, 0, 0, 1, 0, 0, 0, 1,
+        ];
+        let device = Device::new(&mut bytes.iter()).unwrap();
+        EmulatedExport::new(Sink { device }, "1-1")
+    }
+
+    #[test]
+    fn unlink_of_completed_urb_sends_ret_submit_first() {
+        let mut export = export();
+        let mut pending = HashMap::new();
+        let submit = CmdSubmit {
+            header: UsbIpHeader {
+                command: USBIP_CMD_SUBMIT,
+                seqnum: 1,
+                direction: USBIP_DIR_OUT,
+                ep: 1,
+                ..Default::default()
+            },
+            transfer_buffer_length: 4,
+            data: vec![1, 2, 3, 4],
+            ..Default::default()
+        };
+        export.submit(submit).unwrap();
+        pending.insert(1, false);
+        let unlink = CmdUnlink {
+            header: UsbIpHeader {
+                command: USBIP_CMD_UNLINK,
+                seqnum: 2,
+                ..Default::default()
+            },
+            unlink_seqnum: 1,
+        };
+
+        let mut out = vec![];
+        UsbIpServer::unlink(&mut export, &mut out, &mut pending, unlink).unwrap();
+
+        let mut r = &out[..];
+        let header = UsbIpHeader::read(&mut r).unwrap();
+        assert_eq!((header.command, header.seqnum), (USBIP_RET_SUBMIT, 1));
+        let ret = RetSubmit::read_body(header, &mut r, false).unwrap();
+        assert_eq!((ret.status, ret.actual_length), (0, 4));
+        let header = UsbIpHeader::read(&mut r).unwrap();
+        assert_eq!((header.command, header.seqnum), (USBIP_RET_UNLINK, 2));
+        assert_eq!(RetUnlink::read_body(header, &mut r).unwrap().status, 0);
+        assert!(r.is_empty());
+        assert!(pending.is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+use std::io::ErrorKind;
+use std::path::Path;
+use usbapi::{DeviceFilter, Speed, UsbEnumerate};
+
+fn fixture() -> UsbEnumerate {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/sysfs");
+    UsbEnumerate::from_sysfs_root(root).unwrap()
+}
+
+#[test]
+fn unreadable_devices_are_skipped_and_collected() {
+    let usb = fixture();
+    let mut found: Vec<&str> = usb
+        .devices()
+        .values()
+        .map(|d| d.port_path.as_str())
+        .collect();
+    found.sort_unstable();
+    assert_eq!(found, ["1-1", "1-1.2", "1-1.3", "usb1"]);
+
+    let mut skipped: Vec<String> = usb
+        .errors()
+        .iter()
+        .map(|e| e.path.file_name().unwrap().to_string_lossy().to_string())
+        .collect();
+    skipped.sort_unstable();
+    assert_eq!(skipped, ["1-4", "1-5"]);
+    let missing = usb.errors().iter().find(|e| e.path.ends_with("1-4"));
+    assert_eq!(missing.unwrap().error.kind(), ErrorKind::NotFound);
+    let invalid = usb.errors().iter().find(|e| e.path.ends_with("1-5"));
+    assert_eq!(invalid.unwrap().error.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn sysfs_attributes_are_read() {
+    let usb = fixture();
+    let device = usb.get_device_from_port_path("1-1.2").unwrap();
+    assert_eq!((device.bus_num, device.dev_num), (1, 3));
+    assert_eq!(device.manufacturer, "STMicroelectronics");
+    assert_eq!(device.serial, "ABC123");
+    assert_eq!(device.speed, Speed::Full);
+    assert_eq!(device.parent.as_deref(), Some("1-1"));
+    assert_eq!(device.port_number, 2);
+}
+
+#[test]
+fn find_one() {
+    let usb = fixture();
+    let stm = DeviceFilter::new().vid_pid(0x0483, 0x5740);
+    let error = usb.find_one(&stm).unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    let device = usb.find_one(&stm.serial("DEF456")).unwrap();
+    assert_eq!(device.port_path, "1-1.3");
+    let error = usb
+        .find_one(&DeviceFilter::new().vid_pid(0x1209, 0x0001))
+        .unwrap_err();
+    assert_eq!(error.kind(), ErrorKind::NotFound);
+}
+
+#[test]
+fn tree() {
+    let usb = fixture();
+    let roots = usb.tree();
+    assert_eq!(roots.len(), 1);
+    let root = &roots[0];
+    assert_eq!(root.device.port_path, "usb1");
+    assert_eq!(root.children.len(), 1);
+    let hub = &root.children[0];
+    assert_eq!(hub.device.port_path, "1-1");
+    let ports: Vec<&str> = hub
+        .children
+        .iter()
+        .map(|c| c.device.port_path.as_str())
+        .collect();
+    assert_eq!(ports, ["1-1.2", "1-1.3"]);
+    assert!(root.find("1-1.3").is_some());
+}
+
+#[test]
+fn missing_root_fails() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/missing");
+    assert!(UsbEnumerate::from_sysfs_root(root).is_err());
+}